@@ -13,6 +13,29 @@ impl Plugin for ActionsPlugin {
     }
 }
 
+/// Converts the cursor position in a window to world coordinates, if the cursor is in the window.
+pub fn cursor_world_position(
+    window: &Window,
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+) -> Option<Vec2> {
+    let position = window.cursor_position()?;
+    // get the size of the window
+    let window_size = Vec2::new(window.width() as f32, window.height() as f32);
+
+    // convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
+    let ndc = (position / window_size) * 2.0 - Vec2::ONE;
+
+    // matrix for undoing the projection and camera transform
+    let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
+
+    // use it to convert ndc to world-space coordinates
+    let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
+
+    // reduce it to a 2D value
+    Some(world_pos.truncate())
+}
+
 #[derive(Default)]
 pub struct Actions {
     pub player_movement: Option<Vec2>,
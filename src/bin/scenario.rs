@@ -0,0 +1,124 @@
+//! Headless runner for MovingAI `.scen` files.
+//!
+//! Usage: `cargo run --release --bin scenario -- <file.scen> [map directory]`
+//!
+//! Every query is run through `PathMesh::path` on a navmesh built by the same generator as the
+//! game, and its length is checked against the optimal octile length from the scenario.
+//! The map directory defaults to the directory of the scenario file.
+
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use bevy_game::movingai::{is_length_consistent, MovingAiMap, Scenario, CELL_SIZE};
+use bevy_pathmesh::PathMesh;
+
+/// Tolerance on path lengths, in cells. `.scen` lengths are rounded to 8 decimals and the mesh
+/// uses `f32`.
+const EPSILON: f32 = 0.01;
+
+fn find_map(map_dir: &Path, map: &str) -> PathBuf {
+    let path = map_dir.join(map);
+    if path.exists() {
+        return path;
+    }
+    // Scenarios usually reference maps with the path they had in the benchmark archive
+    map_dir.join(Path::new(map).file_name().unwrap_or_default())
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let scen_path = match args.next() {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprintln!("usage: scenario <file.scen> [map directory]");
+            return ExitCode::FAILURE;
+        }
+    };
+    let map_dir = args.next().map(PathBuf::from).unwrap_or_else(|| {
+        scen_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    });
+
+    let scenario = match Scenario::load(&scen_path) {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            eprintln!("unable to load {}: {}", scen_path.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut meshes: HashMap<String, PathMesh> = HashMap::new();
+    let mut mismatches = 0;
+    let mut total = Duration::ZERO;
+    let mut slowest = Duration::ZERO;
+
+    println!("bucket\tstart\tgoal\texpected\tfound\ttime");
+    for query in scenario.queries.iter() {
+        if !meshes.contains_key(&query.map) {
+            let map_path = find_map(&map_dir, &query.map);
+            let map = match MovingAiMap::load(&map_path) {
+                Ok(map) => map,
+                Err(err) => {
+                    eprintln!("unable to load {}: {}", map_path.display(), err);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let start = Instant::now();
+            let mesh = PathMesh::from_polyanya_mesh(map.build_navmesh());
+            eprintln!(
+                "built navmesh for {} ({}x{}) in {:?}",
+                query.map,
+                map.width,
+                map.height,
+                start.elapsed()
+            );
+            meshes.insert(query.map.clone(), mesh);
+        }
+        let mesh = &meshes[&query.map];
+
+        let start = Instant::now();
+        let path = mesh.path(query.start * CELL_SIZE, query.goal * CELL_SIZE);
+        let duration = start.elapsed();
+        total += duration;
+        slowest = slowest.max(duration);
+
+        let length = path.map(|path| path.length / CELL_SIZE);
+        let consistent = is_length_consistent(length, query.optimal_length, EPSILON);
+        if !consistent {
+            mismatches += 1;
+        }
+        println!(
+            "{}\t{}\t{}\t{:.4}\t{}\t{:?}{}",
+            query.bucket,
+            query.start,
+            query.goal,
+            query.optimal_length,
+            length.map_or("none".to_string(), |length| format!("{:.4}", length)),
+            duration,
+            if consistent { "" } else { "\tMISMATCH" }
+        );
+    }
+
+    let count = scenario.queries.len();
+    println!(
+        "{} queries, {} mismatches, total {:?}, mean {:?}, slowest {:?}",
+        count,
+        mismatches,
+        total,
+        total / count.max(1) as u32,
+        slowest
+    );
+
+    if mismatches > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,500 @@
+//! Formations for the groups of navigators sent somewhere by the player, see [`FormationOrder`].
+//!
+//! An anchor goes along the path of the group at the speed of its slowest member, and each member
+//! heads for its slot around the anchor, moved on the navmesh. When a member can't go straight to
+//! its slot, for example in a chokepoint, it follows the trail of the anchor and takes its slot
+//! back once through. The anchor waits for half of the group to be in formation.
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    navigation::{
+        FindingPath, FlowTarget, NavigationSettings, Navigator, Path, PathPriority, PathRequest,
+        PathRequestId, PathRequestIds, PathResponse, Target, TempNavmesh,
+    },
+    GameState,
+};
+
+/// Distance to its slot, in slots, under which a member is in formation
+const IN_FORMATION_DISTANCE: f32 = 3.0;
+/// Fraction of the members that must be in formation for the anchor to move
+const IN_FORMATION_FRACTION: f32 = 0.5;
+
+pub struct FormationPlugin;
+
+impl Plugin for FormationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FormationSettings>()
+            .add_event::<FormationOrder>()
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(change_formation)
+                    .with_system(create_formations)
+                    .with_system(move_formations.after(create_formations)),
+            )
+            // Read after the formations spawned during the update exist
+            .add_system_to_stage(CoreStage::PostUpdate, receive_formation_paths)
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_formations));
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FormationShape {
+    /// Rows four times wider than the formation is deep
+    Line,
+    /// Four times deeper than wide
+    Column,
+    /// A triangle pointing forward
+    Wedge,
+    /// A filled square
+    Box,
+}
+
+impl FormationShape {
+    /// Slots from the front row to the back one, each row from left to right. `x` goes to the
+    /// right and `y` forward, the formation is centered on the origin.
+    fn rows(self, count: usize, spacing: f32) -> Vec<Vec<Vec2>> {
+        let mut sizes = Vec::new();
+        if let FormationShape::Wedge = self {
+            let mut row_size = 1;
+            let mut left = count;
+            while left > 0 {
+                sizes.push(row_size.min(left));
+                left -= row_size.min(left);
+                row_size += 2;
+            }
+        } else if count > 0 {
+            let side = (count as f32).sqrt();
+            let columns = match self {
+                FormationShape::Line => (side * 2.0).ceil(),
+                FormationShape::Column => (side / 2.0).ceil(),
+                _ => side.ceil(),
+            } as usize;
+            let columns = columns.clamp(1, count);
+            sizes = vec![columns; count / columns];
+            if count % columns > 0 {
+                sizes.push(count % columns);
+            }
+        }
+
+        let depth = sizes.len();
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(row, size)| {
+                let y = ((depth - 1) as f32 / 2.0 - row as f32) * spacing;
+                (0..*size)
+                    .map(|column| {
+                        Vec2::new((column as f32 - (*size - 1) as f32 / 2.0) * spacing, y)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Next shape when pressing F, `None` after the last one
+    fn next(shape: Option<Self>) -> Option<Self> {
+        match shape {
+            None => Some(FormationShape::Line),
+            Some(FormationShape::Line) => Some(FormationShape::Column),
+            Some(FormationShape::Column) => Some(FormationShape::Wedge),
+            Some(FormationShape::Wedge) => Some(FormationShape::Box),
+            Some(FormationShape::Box) => None,
+        }
+    }
+}
+
+pub struct FormationSettings {
+    /// Shape of the next formations, groups move without formation when `None`
+    pub shape: Option<FormationShape>,
+    /// Distance between neighbouring slots
+    pub spacing: f32,
+}
+
+impl Default for FormationSettings {
+    fn default() -> Self {
+        FormationSettings {
+            shape: None,
+            spacing: 10.0,
+        }
+    }
+}
+
+/// Sends `members` to `target` in the shape of `FormationSettings::shape`. Members that can't
+/// reach the target go there on their own.
+pub struct FormationOrder {
+    pub members: Vec<Entity>,
+    pub navmesh: Entity,
+    pub target: Vec2,
+}
+
+/// A group moving in formation, its members have a [`FormationMember`]
+#[derive(Component)]
+struct Formation {
+    navmesh: Entity,
+    target: Vec2,
+    /// Speed of the slowest member
+    speed: f32,
+    request: PathRequestId,
+    /// Where the anchor starts
+    start: Vec2,
+    /// Path of the anchor from `start`, empty until it's computed
+    path: Vec<Vec2>,
+    /// Distance travelled by the anchor along `path`
+    progress: f32,
+    facing: Vec2,
+    /// Fraction of the members close to their slot during the last frame
+    in_formation: f32,
+}
+
+/// Navigator moving in a [`Formation`] toward its slot
+#[derive(Component)]
+pub struct FormationMember {
+    formation: Entity,
+    /// Position of the slot relative to the anchor, see [`FormationShape::rows`]
+    offset: Vec2,
+}
+
+fn change_formation(keyboard_input: Res<Input<KeyCode>>, mut settings: ResMut<FormationSettings>) {
+    if keyboard_input.just_pressed(KeyCode::F) {
+        settings.shape = FormationShape::next(settings.shape);
+    }
+}
+
+/// Sends a navigator to `target` with a path of its own
+fn go_alone(commands: &mut Commands, entity: Entity, target: Vec2) {
+    commands.entity(entity).insert(Target {
+        target,
+        priority: PathPriority::Player,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_formations(
+    mut commands: Commands,
+    mut orders: EventReader<FormationOrder>,
+    navigators: Query<(&Transform, &Navigator)>,
+    mesh_q: Query<&TempNavmesh>,
+    settings: Res<FormationSettings>,
+    navigation_settings: Res<NavigationSettings>,
+    mut request_ids: ResMut<PathRequestIds>,
+    mut requests: EventWriter<PathRequest>,
+) {
+    for order in orders.iter() {
+        let temp = match mesh_q.get(order.navmesh) {
+            Ok(temp) => temp,
+            Err(_) => continue,
+        };
+        let target_component = temp
+            .nearest_point_where(order.target, navigation_settings.snap_radius, |_| true)
+            .map(|(polygon, _)| temp.component(polygon));
+
+        let mut members = Vec::with_capacity(order.members.len());
+        for entity in &order.members {
+            let (transform, navigator) = match navigators.get(*entity) {
+                Ok(navigator) => navigator,
+                Err(_) => continue,
+            };
+            let position = transform.translation.truncate();
+            let component = temp
+                .polygon_at(position)
+                .map(|polygon| temp.component(polygon));
+            commands
+                .entity(*entity)
+                .remove::<Target>()
+                .remove::<Path>()
+                .remove::<FindingPath>()
+                .remove::<FlowTarget>()
+                .remove::<FormationMember>();
+            if settings.shape.is_some() && component.is_some() && component == target_component {
+                members.push((*entity, position, navigator.speed));
+            } else {
+                go_alone(&mut commands, *entity, order.target);
+            }
+        }
+        let shape = match settings.shape {
+            Some(shape) if !members.is_empty() => shape,
+            _ => continue,
+        };
+
+        let center = members
+            .iter()
+            .map(|(_, position, _)| *position)
+            .sum::<Vec2>()
+            / members.len() as f32;
+        // The center may be in a wall, the anchor starts from the closest member instead
+        let start = members
+            .iter()
+            .map(|(_, position, _)| *position)
+            .min_by(|a, b| {
+                a.distance_squared(center)
+                    .total_cmp(&b.distance_squared(center))
+            })
+            .unwrap_or(center);
+        let facing = (order.target - center).try_normalize().unwrap_or(Vec2::Y);
+        let speed = members
+            .iter()
+            .map(|(_, _, speed)| *speed)
+            .fold(f32::INFINITY, f32::min);
+
+        // A single search for the whole group
+        let request = request_ids.next_id();
+        requests.send(PathRequest {
+            id: request,
+            priority: PathPriority::Player,
+            from: start,
+            to: order.target,
+            navmesh: Some(order.navmesh),
+            // The formation entity doesn't exist before the commands are applied, the response
+            // is found with its id
+            requester: None,
+        });
+        let formation = commands
+            .spawn()
+            .insert(Formation {
+                navmesh: order.navmesh,
+                target: order.target,
+                speed,
+                request,
+                start,
+                path: Vec::new(),
+                progress: 0.0,
+                facing,
+                in_formation: 0.0,
+            })
+            .id();
+
+        // The members in front take the front rows, and keep their side within their row so that
+        // they don't cross each other
+        let right = Vec2::new(facing.y, -facing.x);
+        members.sort_by(|(_, a, _), (_, b, _)| b.dot(facing).total_cmp(&a.dot(facing)));
+        let mut remaining = &mut members[..];
+        for row in shape.rows(remaining.len(), settings.spacing) {
+            let (row_members, rest) = std::mem::take(&mut remaining).split_at_mut(row.len());
+            row_members.sort_by(|(_, a, _), (_, b, _)| a.dot(right).total_cmp(&b.dot(right)));
+            for ((entity, _, _), offset) in row_members.iter().zip(row) {
+                commands
+                    .entity(*entity)
+                    .insert(FormationMember { formation, offset });
+            }
+            remaining = rest;
+        }
+    }
+}
+
+/// Gives the formations their path, or sends the members on their own when there is none.
+fn receive_formation_paths(
+    mut commands: Commands,
+    mut responses: EventReader<PathResponse>,
+    mut formations: Query<(Entity, &mut Formation)>,
+    members: Query<(Entity, &FormationMember)>,
+) {
+    for response in responses.iter() {
+        if response.requester.is_some() {
+            continue;
+        }
+        let (entity, mut formation) = match formations
+            .iter_mut()
+            .find(|(_, formation)| formation.request == response.id)
+        {
+            Some(formation) => formation,
+            None => continue,
+        };
+        match &response.path {
+            Some(path) => {
+                formation.path = std::iter::once(formation.start)
+                    .chain(path.iter().copied())
+                    .collect();
+            }
+            None => {
+                let target = formation.target;
+                members.for_each(|(member, membership)| {
+                    if membership.formation == entity {
+                        commands.entity(member).remove::<FormationMember>();
+                        go_alone(&mut commands, member, target);
+                    }
+                });
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+/// Position at `distance` along `path`, the direction there and the index of the segment
+fn point_along(path: &[Vec2], distance: f32) -> (Vec2, Option<Vec2>, usize) {
+    let mut left = distance;
+    let mut direction = None;
+    for (index, segment) in path.windows(2).enumerate() {
+        let length = segment[0].distance(segment[1]);
+        if length > f32::EPSILON {
+            direction = Some((segment[1] - segment[0]) / length);
+        }
+        if left <= length {
+            return (
+                segment[0].lerp(segment[1], left / length.max(f32::EPSILON)),
+                direction,
+                index,
+            );
+        }
+        left -= length;
+    }
+    (
+        path.last().copied().unwrap_or_default(),
+        direction,
+        path.len().saturating_sub(2),
+    )
+}
+
+/// Points of `path` from the one after the closest to `position`, up to segment `segment`
+fn trail(path: &[Vec2], segment: usize, position: Vec2) -> Vec<Vec2> {
+    let closest = path
+        .windows(2)
+        .take(segment + 1)
+        .enumerate()
+        .map(|(index, points)| {
+            let edge = points[1] - points[0];
+            let along = ((position - points[0]).dot(edge)
+                / edge.length_squared().max(f32::EPSILON))
+            .clamp(0.0, 1.0);
+            (index, (points[0] + edge * along).distance_squared(position))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+        .unwrap_or(segment);
+    path[(closest + 1).min(path.len())..(segment + 1).min(path.len())].to_vec()
+}
+
+/// How a formation is doing this frame
+struct FormationState {
+    anchor: Vec2,
+    segment: usize,
+    members: Vec<Entity>,
+    in_formation: usize,
+}
+
+/// Moves the anchors of the formations, then sends each member toward its slot.
+fn move_formations(
+    mut commands: Commands,
+    mut formations: Query<(Entity, &mut Formation)>,
+    mut members: Query<(Entity, &Transform, &FormationMember, Option<&mut Path>)>,
+    mesh_q: Query<&TempNavmesh>,
+    settings: Res<FormationSettings>,
+    navigation_settings: Res<NavigationSettings>,
+    time: Res<Time>,
+) {
+    let mut states = HashMap::new();
+    for (entity, mut formation) in formations.iter_mut() {
+        // Waiting for the path
+        if formation.path.is_empty() {
+            continue;
+        }
+        if formation.in_formation >= IN_FORMATION_FRACTION {
+            formation.progress += formation.speed * time.delta_seconds();
+        }
+        let (anchor, facing, segment) = point_along(&formation.path, formation.progress);
+        if let Some(facing) = facing {
+            formation.facing = facing;
+        }
+        states.insert(
+            entity,
+            FormationState {
+                anchor,
+                segment,
+                members: Vec::new(),
+                in_formation: 0,
+            },
+        );
+    }
+
+    members.for_each_mut(|(entity, transform, member, path)| {
+        let formation = match formations.get(member.formation) {
+            Ok((_, formation)) => formation,
+            Err(_) => {
+                commands.entity(entity).remove::<FormationMember>();
+                return;
+            }
+        };
+        let (state, temp) = match (
+            states.get_mut(&member.formation),
+            mesh_q.get(formation.navmesh),
+        ) {
+            (Some(state), Ok(temp)) => (state, temp),
+            _ => return,
+        };
+        state.members.push(entity);
+
+        let position = transform.translation.truncate();
+        let facing = formation.facing;
+        let slot = state.anchor
+            + Vec2::new(facing.y, -facing.x) * member.offset.x
+            + facing * member.offset.y;
+        let slot = if temp.navmesh.is_in_mesh(slot) {
+            slot
+        } else {
+            let anchor_component = temp
+                .polygon_at(state.anchor)
+                .map(|polygon| temp.component(polygon));
+            temp.nearest_point_where(slot, navigation_settings.snap_radius, |polygon| {
+                Some(temp.component(polygon)) == anchor_component
+            })
+            .map(|(_, slot)| slot)
+            .unwrap_or(state.anchor)
+        };
+        if slot.distance(position) < settings.spacing * IN_FORMATION_DISTANCE {
+            state.in_formation += 1;
+        }
+
+        let points = if temp.is_segment_in_mesh(position, slot) {
+            vec![slot]
+        } else {
+            let mut points = trail(&formation.path, state.segment, position);
+            points.extend([state.anchor, slot]);
+            points
+        };
+        match path {
+            Some(mut path) => {
+                path.path = points;
+                path.navmesh_version = temp.version;
+                path.polygons.clear();
+            }
+            None => {
+                commands.entity(entity).insert(Path {
+                    path: points,
+                    navmesh_version: temp.version,
+                    polygons: Vec::new(),
+                });
+            }
+        }
+    });
+
+    for (entity, mut formation) in formations.iter_mut() {
+        let state = match states.get(&entity) {
+            Some(state) => state,
+            None => continue,
+        };
+        if state.members.is_empty() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        formation.in_formation = state.in_formation as f32 / state.members.len() as f32;
+
+        // Arrived, the members finish going to their slot on their own
+        let length: f32 = formation
+            .path
+            .windows(2)
+            .map(|segment| segment[0].distance(segment[1]))
+            .sum();
+        if formation.progress >= length && formation.in_formation >= IN_FORMATION_FRACTION {
+            for member in &state.members {
+                commands.entity(*member).remove::<FormationMember>();
+            }
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+fn despawn_formations(mut commands: Commands, formations: Query<Entity, With<Formation>>) {
+    for entity in formations.iter() {
+        commands.entity(entity).despawn();
+    }
+}
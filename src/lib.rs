@@ -1,16 +1,23 @@
 mod actions;
 mod audio;
+mod formation;
 mod loading;
 mod many;
 mod map;
 mod menu;
+pub mod navigation;
 mod player;
+mod selection;
 
 use crate::actions::ActionsPlugin;
 use crate::audio::InternalAudioPlugin;
+use crate::formation::FormationPlugin;
 use crate::loading::LoadingPlugin;
+use crate::many::MyNavPlugin;
 use crate::menu::MenuPlugin;
+use crate::navigation::NavigationPlugin;
 use crate::player::PlayerPlugin;
+use crate::selection::SelectionPlugin;
 
 use bevy::app::App;
 // #[cfg(debug_assertions)]
@@ -21,6 +28,12 @@ use bevy_ecs_tilemap::TilemapPlugin;
 use bevy_inspector_egui::{WorldInspectorPlugin, WorldInspectorParams};
 use map::MapPlugin;
 
+pub use many::{SpawnSettings, SpawnZone};
+pub use map::{
+    load_map_file, movingai, save_map_file, ConnectivitySettings, MapFile, MapFileError,
+    MapPalette, MapSource, MapTiles, PaletteEntry, TiledSettings,
+};
+
 // This example game uses States to separate logic
 // See https://bevy-cheatbook.github.io/programming/states.html
 // Or https://github.com/bevyengine/bevy/blob/main/examples/ecs/state.rs
@@ -33,6 +46,8 @@ enum GameState {
     // Here the menu is drawn and waiting for player interaction
     Menu,
     MapGeneration,
+    // Cleans up the generated map, see `map::ConnectivitySettings`
+    MapPostProcessing,
     NavMeshGeneration,
 }
 
@@ -47,6 +62,10 @@ impl Plugin for GamePlugin {
             .add_plugin(ActionsPlugin)
             .add_plugin(InternalAudioPlugin)
             .add_plugin(PlayerPlugin)
+            .add_plugin(NavigationPlugin)
+            .add_plugin(MyNavPlugin)
+            .add_plugin(SelectionPlugin)
+            .add_plugin(FormationPlugin)
             .add_plugin(TilemapPlugin);
 
         // #[cfg(debug_assertions)]
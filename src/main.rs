@@ -5,13 +5,29 @@ use bevy::prelude::{App, ClearColor, Color, Msaa, NonSend, WindowDescriptor};
 use bevy::window::{PresentMode, WindowId};
 use bevy::winit::WinitWindows;
 use bevy::DefaultPlugins;
-use bevy_game::GamePlugin;
+use bevy_game::{GamePlugin, MapSource};
+use std::env;
 use std::io::Cursor;
+use std::process;
 use winit::window::Icon;
 
 fn main() {
-    App::new()
-        .insert_resource(Msaa { samples: 1 })
+    let mut app = App::new();
+
+    // `cargo run -- path/to/map` loads a `.png`, Tiled or saved `.ron` map instead of generating
+    if let Some(path) = env::args().nth(1) {
+        match MapSource::from_path(path) {
+            Ok(map_source) => {
+                app.insert_resource(map_source);
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    app.insert_resource(Msaa { samples: 1 })
         .insert_resource(ClearColor(Color::rgb(0.4, 0.4, 0.4)))
         .insert_resource(WindowDescriptor {
             width: 1920.,
@@ -1,27 +1,36 @@
-use std::{
-    collections::VecDeque,
-    sync::{Arc, RwLock},
-    time::Duration,
-};
+//! Demo of many agents wandering on the map, using the [`crate::navigation`] plugin.
+//! Right-click sends the agents selected with [`crate::selection`] to the cursor, shift-right-click
+//! adds a waypoint after their current destination. F cycles through the
+//! [`crate::formation`] shapes the selected agents keep while moving.
+
+use std::{collections::VecDeque, time::Duration};
 
 use bevy::{
     core::TaskPoolThreadAssignmentPolicy,
     diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin},
-    math::Vec3Swizzles,
     prelude::*,
     sprite::MaterialMesh2dBundle,
-    tasks::AsyncComputeTaskPool,
     // time::FixedTimestep,
-    utils::Instant,
     window::WindowResized,
 };
 
-use bevy_pathmesh::PathmeshPlugin;
 use bevy_prototype_debug_lines::DebugLines;
 
-use crate::{loading::FontAssets, map::TempNavmesh, GameState};
+use crate::{
+    actions::cursor_world_position,
+    formation::{FormationMember, FormationOrder, FormationSettings},
+    loading::FontAssets,
+    map::EditorText,
+    navigation::{
+        clear_navigation, is_on_screen, FindingPath, FlowTarget, NavigationSettings,
+        NavigationStats, Navigator, NavmeshId, Path, PathPriority, Target, TaskMode, TempNavmesh,
+    },
+    selection::Selected,
+    GameState,
+};
 
-const SPAWN_LIMIT: u64 = 10000;
+/// Attempts at finding a point of a spawn zone that's on the navmesh
+const SPAWN_ATTEMPTS: usize = 8;
 
 pub struct MyNavPlugin;
 
@@ -36,22 +45,27 @@ impl Plugin for MyNavPlugin {
                 },
                 ..default()
             })
-            .add_plugin(PathmeshPlugin)
-            .init_resource::<Stats>()
             .init_resource::<NavigatorCount>()
-            .insert_resource(TaskMode::Blocking)
+            .init_resource::<SpawnSettings>()
             .insert_resource(DisplayMode::Line)
-            .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(setup))
+            .add_system_set(
+                SystemSet::on_enter(GameState::Playing)
+                    .with_system(setup)
+                    .with_system(validate_spawn_zones),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Playing)
+                    .with_system(cleanup)
+                    .with_system(clear_navigation),
+            )
             .add_system_set(
                 SystemSet::on_update(GameState::Playing)
                     // .with_system(on_mesh_change)
                     .with_system(go_somewhere)
-                    .with_system(compute_paths)
-                    .with_system(poll_path_tasks)
-                    .with_system(move_navigator)
                     .with_system(display_path)
                     .with_system(mode_change)
                     .with_system(go_to_mouse)
+                    .with_system(follow_orders.after(go_to_mouse)),
             )
             .add_system_set(
                 SystemSet::on_update(GameState::Playing)
@@ -63,12 +77,6 @@ impl Plugin for MyNavPlugin {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum TaskMode {
-    Async,
-    Blocking,
-}
-
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum DisplayMode {
     Line,
@@ -144,6 +152,38 @@ fn setup(mut commands: Commands, font_assets: Res<FontAssets>) {
                     color: Color::WHITE,
                 },
             ),
+            TextSection::new(
+                "Queued paths: ",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "0\n",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "Path cache hits: ",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "0\n",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                },
+            ),
             TextSection::new(
                 "space - ",
                 TextStyle {
@@ -170,6 +210,38 @@ fn setup(mut commands: Commands, font_assets: Res<FontAssets>) {
             ),
             TextSection::new(
                 "\n",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 15.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "o - ",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 15.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 15.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "f - ",
+                TextStyle {
+                    font: font.clone_weak(),
+                    font_size: 15.0,
+                    color: Color::WHITE,
+                },
+            ),
+            TextSection::new(
+                "",
                 TextStyle {
                     font,
                     font_size: 15.0,
@@ -190,6 +262,20 @@ fn setup(mut commands: Commands, font_assets: Res<FontAssets>) {
     });
 }
 
+/// Despawns the agents and the UI before a new map is generated, the navigation state is cleared
+/// by [`clear_navigation`].
+fn cleanup(
+    mut commands: Commands,
+    navigators: Query<Entity, With<Navigator>>,
+    ui_query: Query<Entity, (With<Text>, Without<EditorText>)>,
+    mut navigator_count: ResMut<NavigatorCount>,
+) {
+    for entity in navigators.iter().chain(ui_query.iter()) {
+        commands.entity(entity).despawn_recursive();
+    }
+    *navigator_count = NavigatorCount::default();
+}
+
 #[allow(unused)]
 fn on_mesh_change(
     mut commands: Commands,
@@ -222,173 +308,271 @@ fn on_mesh_change(
     }
 }
 
+/// Color of the lines displaying the path of an agent
 #[derive(Component)]
-struct Navigator {
-    speed: f32,
-    color: Color,
+pub(crate) struct PathColor(pub Color);
+
+// #[derive(Resource)]
+struct NavigatorCount(u64);
+
+impl Default for NavigatorCount {
+    fn default() -> Self {
+        NavigatorCount(0)
+    }
 }
 
-#[derive(Component)]
-struct Target {
-    target: Vec2,
+/// An area of the map where navigators can spawn, in world coordinates.
+#[derive(Clone, Debug)]
+pub enum SpawnZone {
+    Point(Vec2),
+    Rect { min: Vec2, max: Vec2 },
+    Polygon(Vec<Vec2>),
 }
 
-#[derive(Component)]
-struct Path {
-    path: Vec<Vec2>,
+impl SpawnZone {
+    fn contains(&self, point: Vec2) -> bool {
+        match self {
+            SpawnZone::Point(zone_point) => *zone_point == point,
+            SpawnZone::Rect { min, max } => point.cmpge(*min).all() && point.cmple(*max).all(),
+            SpawnZone::Polygon(vertices) => {
+                // Even-odd rule
+                let mut inside = false;
+                for i in 0..vertices.len() {
+                    let (a, b) = (vertices[i], vertices[(i + 1) % vertices.len()]);
+                    if (a.y > point.y) != (b.y > point.y)
+                        && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    fn random_point(&self, rng: &fastrand::Rng) -> Option<Vec2> {
+        let random_in = |min: Vec2, max: Vec2| {
+            Vec2::new(
+                min.x + rng.f32() * (max.x - min.x),
+                min.y + rng.f32() * (max.y - min.y),
+            )
+        };
+        match self {
+            SpawnZone::Point(point) => Some(*point),
+            SpawnZone::Rect { min, max } => Some(random_in(*min, *max)),
+            SpawnZone::Polygon(vertices) => {
+                let min = vertices
+                    .iter()
+                    .fold(Vec2::splat(f32::MAX), |min, v| min.min(*v));
+                let max = vertices
+                    .iter()
+                    .fold(Vec2::splat(f32::MIN), |max, v| max.max(*v));
+                (0..SPAWN_ATTEMPTS)
+                    .map(|_| random_in(min, max))
+                    .find(|point| self.contains(*point))
+            }
+        }
+    }
 }
 
-// #[derive(Resource)]
-struct NavigatorCount(u64);
+/// Where and how fast navigators are spawned on each navmesh.
+pub struct SpawnSettings {
+    /// Navigators spawn in a random zone, or anywhere on the navmesh when there are none
+    pub zones: Vec<SpawnZone>,
+    /// Seconds between two batches
+    pub interval: f32,
+    /// Navigators spawned on each navmesh in a batch
+    pub batch_size: usize,
+    /// Total number of navigators
+    pub limit: u64,
+}
 
-impl Default for NavigatorCount {
+impl Default for SpawnSettings {
     fn default() -> Self {
-        NavigatorCount(0)
+        SpawnSettings {
+            zones: Vec::new(),
+            interval: 0.0,
+            batch_size: 40,
+            limit: 10000,
+        }
+    }
+}
+
+/// Random point of the navmesh in one of the spawn zones
+fn spawn_point(temp: &TempNavmesh, zones: &[SpawnZone], rng: &fastrand::Rng) -> Option<Vec2> {
+    if zones.is_empty() {
+        return temp.random_point(rng, None, None);
+    }
+    (0..SPAWN_ATTEMPTS)
+        .filter_map(|_| zones[rng.usize(..zones.len())].random_point(rng))
+        .find(|point| temp.navmesh.is_in_mesh(*point))
+}
+
+/// Warns about the spawn zones where no navigator can spawn on the new map
+fn validate_spawn_zones(spawn_settings: Res<SpawnSettings>, mesh_q: Query<&TempNavmesh>) {
+    let rng = fastrand::Rng::new();
+    for zone in &spawn_settings.zones {
+        let on_navmesh = mesh_q.iter().any(|temp| {
+            (0..SPAWN_ATTEMPTS * 4)
+                .filter_map(|_| zone.random_point(&rng))
+                .any(|point| temp.navmesh.is_in_mesh(point))
+        });
+        if !on_navmesh {
+            warn!("spawn zone {:?} isn't on any navmesh", zone);
+        }
     }
 }
 
 fn spawn(
     mut commands: Commands,
     mut navigator_count: ResMut<NavigatorCount>,
-    transform_q: Query<(&Transform, &TempNavmesh)>,
+    spawn_settings: Res<SpawnSettings>,
+    time: Res<Time>,
+    mut since_last_batch: Local<f32>,
+    mesh_q: Query<(Entity, &TempNavmesh)>,
 ) {
-    if navigator_count.0 >= SPAWN_LIMIT {
+    if navigator_count.0 >= spawn_settings.limit {
         return;
     }
-
-    let (transform, navmesh_container) = transform_q.single();
-    let mesh_size = &navmesh_container.dimensions;
+    *since_last_batch += time.delta_seconds();
+    if *since_last_batch < spawn_settings.interval {
+        return;
+    }
+    *since_last_batch = 0.0;
 
     let rng = fastrand::Rng::new();
 
-    let in_mesh_starts = [
-        Vec2::new(575.0, 410.0),
-        Vec2::new(387.0, 524.0),
-        Vec2::new(762.0, 692.0),
-        Vec2::new(991.0, 426.0),
-        Vec2::new(746.0, 241.0),
-        Vec2::new(391.0, 231.0),
-        Vec2::new(25.0, 433.0),
-        Vec2::new(300.0, 679.0),
-        Vec2::new(575.0, 410.0),
-        Vec2::new(387.0, 524.0),
-        Vec2::new(762.0, 692.0),
-        Vec2::new(991.0, 426.0),
-        Vec2::new(746.0, 241.0),
-        Vec2::new(391.0, 231.0),
-        Vec2::new(25.0, 433.0),
-        Vec2::new(300.0, 679.0),
-        Vec2::new(575.0, 410.0),
-        Vec2::new(387.0, 524.0),
-        Vec2::new(762.0, 692.0),
-        Vec2::new(991.0, 426.0),
-        Vec2::new(746.0, 241.0),
-        Vec2::new(391.0, 231.0),
-        Vec2::new(25.0, 433.0),
-        Vec2::new(300.0, 679.0),
-        Vec2::new(575.0, 410.0),
-        Vec2::new(387.0, 524.0),
-        Vec2::new(762.0, 692.0),
-        Vec2::new(991.0, 426.0),
-        Vec2::new(746.0, 241.0),
-        Vec2::new(391.0, 231.0),
-        Vec2::new(25.0, 433.0),
-        Vec2::new(300.0, 679.0),
-        Vec2::new(575.0, 410.0),
-        Vec2::new(387.0, 524.0),
-        Vec2::new(762.0, 692.0),
-        Vec2::new(991.0, 426.0),
-        Vec2::new(746.0, 241.0),
-        Vec2::new(391.0, 231.0),
-        Vec2::new(25.0, 433.0),
-        Vec2::new(300.0, 679.0),
-    ];
-
-    in_mesh_starts.iter().for_each(|in_mesh| {
-        navigator_count.0 += 1;
-        let position = *in_mesh + transform.translation.truncate();
-        let color = Color::hsl(rng.f32() * 360.0, 1.0, 0.5).as_rgba();
-        commands
-            .spawn_bundle(SpriteBundle {
-                sprite: Sprite {
-                    color,
-                    custom_size: Some(Vec2::ONE),
+    mesh_q.for_each(|(mesh_entity, temp)| {
+        for _ in 0..spawn_settings.batch_size {
+            if navigator_count.0 >= spawn_settings.limit {
+                return;
+            }
+            let position = if let Some(position) = spawn_point(temp, &spawn_settings.zones, &rng) {
+                position
+            } else {
+                continue;
+            };
+            navigator_count.0 += 1;
+            let color = Color::hsl(rng.f32() * 360.0, 1.0, 0.5).as_rgba();
+            commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color,
+                        custom_size: Some(Vec2::ONE),
+                        ..default()
+                    },
+                    transform: Transform::from_translation(position.extend(1.0))
+                        .with_scale(Vec3::splat(5.0)),
                     ..default()
-                },
-                transform: Transform::from_translation(position.extend(1.0))
-                    .with_scale(Vec3::splat(5.0)),
-                ..default()
-            })
-            .insert(Navigator {
-                speed: rng.f32() * 50.0 + 50.0,
-                color,
-            });
+                })
+                .insert(Navigator::new(rng.f32() * 50.0 + 50.0))
+                .insert(PathColor(color))
+                .insert(NavmeshId(mesh_entity));
+        }
     });
 }
 
-#[derive(Default)]
-struct TaskResult {
-    path: Option<polyanya::Path>,
-    done: bool,
-    delay: f32,
-    duration: f32,
-}
+/// Navigators sent together from which a flow field is used
+const FLOW_FIELD_MIN_GROUP: usize = 32;
 
+#[allow(clippy::too_many_arguments)]
 fn go_to_mouse(
     mut commands: Commands,
-    mesh_q: Query<&TempNavmesh>,
+    mesh_q: Query<(Entity, &TempNavmesh)>,
     windows: Res<Windows>,
     q_camera: Query<(&Camera, &GlobalTransform)>,
     buttons: Res<Input<MouseButton>>,
-    navigators: Query<
-        (Entity, Option<&Path>, Option<&FindingPath>, Option<&Target>),
-        (With<Navigator>,),
-    >,
+    keyboard_input: Res<Input<KeyCode>>,
+    settings: Res<NavigationSettings>,
+    formation_settings: Res<FormationSettings>,
+    mut formation_orders: EventWriter<FormationOrder>,
+    mut navigators: Query<(Entity, &NavmeshId, Option<&mut Orders>), With<Selected>>,
 ) {
     if buttons.just_pressed(MouseButton::Right) {
-        println!("pressed rmb");
-        let temp = mesh_q.single();
-        let navmesh = &temp.navmesh;
-
         let window = windows.get_primary().unwrap();
-
-        if let Some(position) = window.cursor_position() {
-            // cursor is inside the window, position given
-            let (camera, camera_transform) = q_camera.single();
-            // get the size of the window
-            let window_size = Vec2::new(window.width() as f32, window.height() as f32);
-
-            // convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
-            let ndc = (position / window_size) * 2.0 - Vec2::ONE;
-
-            // matrix for undoing the projection and camera transform
-            let ndc_to_world =
-                camera_transform.compute_matrix() * camera.projection_matrix().inverse();
-
-            // use it to convert ndc to world-space coordinates
-            let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
-
-            // reduce it to a 2D value
-            let world_pos: Vec2 = world_pos.truncate();
-
-            if navmesh.is_in_mesh(world_pos) {
-                println!("++++++point: {} is in mesh", world_pos);
+        let (camera, camera_transform) = q_camera.single();
+
+        if let Some(world_pos) = cursor_world_position(window, camera, camera_transform) {
+            // Only the navigators on the clicked navmesh can go there, clicks on a wall are
+            // snapped to the closest navmesh by the navigation plugin
+            let clicked_mesh = mesh_q
+                .iter()
+                .find(|(_, temp)| temp.navmesh.is_in_mesh(world_pos))
+                .map(|(mesh_entity, _)| mesh_entity);
+            let clicked_mesh = if let Some(clicked_mesh) = clicked_mesh {
+                debug!("point {} is in mesh", world_pos);
+                clicked_mesh
+            } else if let Some((clicked_mesh, _)) = mesh_q
+                .iter()
+                .filter_map(|(mesh_entity, temp)| {
+                    let nearest = temp.nearest_point(world_pos, settings.snap_radius)?;
+                    Some((mesh_entity, nearest.distance(world_pos)))
+                })
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            {
+                debug!("point {} is not in mesh, snapping it", world_pos);
+                clicked_mesh
             } else {
-                println!("------point: {} is not in mesh", world_pos);
+                debug!("point {} is too far from the mesh", world_pos);
+                return;
+            };
+
+            let group_size = navigators
+                .iter()
+                .filter(|(_, navmesh_id, _)| navmesh_id.0 == clicked_mesh)
+                .count();
+            // With shift, the order is carried out after the previous ones
+            let append = keyboard_input.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+
+            if !append && formation_settings.shape.is_some() && group_size > 1 {
+                let mut members = Vec::with_capacity(group_size);
+                navigators.for_each_mut(|(entity, navmesh_id, orders)| {
+                    if navmesh_id.0 != clicked_mesh {
+                        return;
+                    }
+                    match orders {
+                        Some(mut orders) => orders.waypoints.clear(),
+                        None => {
+                            commands.entity(entity).insert(Orders::default());
+                        }
+                    }
+                    members.push(entity);
+                });
+                formation_orders.send(FormationOrder {
+                    members,
+                    navmesh: clicked_mesh,
+                    target: world_pos,
+                });
+                return;
             }
 
-            navigators.for_each(|(entity, path, finding_path, target)| {
-                if path.is_some() {
-                    commands.entity(entity).remove::<Path>();
+            let order = Order {
+                target: world_pos,
+                // Large groups share a flow field instead of searching a path each
+                flow_field: group_size >= FLOW_FIELD_MIN_GROUP,
+            };
+            navigators.for_each_mut(|(entity, navmesh_id, orders)| {
+                if navmesh_id.0 != clicked_mesh {
+                    return;
                 }
-                if finding_path.is_some() {
-                    commands.entity(entity).remove::<FindingPath>();
+                match orders {
+                    Some(mut orders) if append => {
+                        orders.waypoints.push_back(order);
+                        return;
+                    }
+                    Some(mut orders) => orders.waypoints.clear(),
+                    // Wandering navigators start obeying right away
+                    None => {
+                        commands.entity(entity).insert(Orders::default());
+                    }
                 }
-                if target.is_some() {
-                    commands.entity(entity).remove::<Target>();
-                }
-
-                commands.entity(entity).insert(Target { target: world_pos });
+                commands
+                    .entity(entity)
+                    .remove::<Path>()
+                    .remove::<FindingPath>()
+                    .remove::<Target>()
+                    .remove::<FormationMember>();
+                order.issue(&mut commands, entity);
             });
         } else {
             // cursor is not inside the window
@@ -396,138 +580,75 @@ fn go_to_mouse(
     }
 }
 
-#[derive(Component)]
-struct FindingPath(Arc<RwLock<TaskResult>>);
-
-fn compute_paths(
-    mut commands: Commands,
-    with_target: Query<(Entity, &Target, &Transform), Changed<Target>>,
-    // meshes: Res<Assets<PathMesh>>,
-    task_mode: Res<TaskMode>,
-    mesh_query: Query<&TempNavmesh>,
-    // mesh: Res<Meshes>,
-) {
-    let temp = mesh_query.single();
-    let mesh = &temp.navmesh;
-    // let mesh = if let Some(mesh) = meshes.get(&mesh.aurora) {
-    //     mesh
-    // } else {
-    //     return;
-    // };
-    with_target.for_each(|(entity, target, transform)| {
-        let in_mesh = transform.translation.truncate();
-
-        let to = target.target;
-        let mesh = mesh.clone();
-        let finding = FindingPath(Arc::new(RwLock::new(TaskResult::default())));
-        let writer = finding.0.clone();
-        let start = Instant::now();
-        let task_mode = *task_mode;
-        AsyncComputeTaskPool::get()
-            .spawn(async move {
-                let delay = (Instant::now() - start).as_secs_f32();
-                let path = if task_mode == TaskMode::Async {
-                    mesh.get_path(in_mesh, to).await
-                } else {
-                    mesh.path(in_mesh, to)
-                };
-                *writer.write().unwrap() = TaskResult {
-                    path,
-                    done: true,
-                    delay,
-                    duration: (Instant::now() - start).as_secs_f32() - delay,
-                };
-            })
-            .detach();
-        commands.entity(entity).insert(finding);
-    });
+/// A destination given by the player
+#[derive(Clone, Copy, Debug)]
+struct Order {
+    target: Vec2,
+    /// Go there with a [`FlowTarget`] instead of a path of its own
+    flow_field: bool,
 }
 
-#[derive(Default)]
-struct Stats {
-    pathfinding_duration: VecDeque<f32>,
-    task_delay: VecDeque<f32>,
+impl Order {
+    fn issue(&self, commands: &mut Commands, entity: Entity) {
+        if self.flow_field {
+            commands.entity(entity).insert(FlowTarget {
+                target: self.target,
+                priority: PathPriority::Player,
+            });
+        } else {
+            commands.entity(entity).insert(Target {
+                target: self.target,
+                priority: PathPriority::Player,
+            });
+        }
+    }
 }
 
-fn poll_path_tasks(
-    mut commands: Commands,
-    computing: Query<(Entity, &FindingPath, &Transform)>,
-    mut stats: ResMut<Stats>,
-    mesh_query: Query<&TempNavmesh>,
-) {
-    let temp = mesh_query.single();
-    let mesh = &temp.navmesh;
-
-    computing.for_each(|(entity, task, transform)| {
-        let mut task = task.0.write().unwrap();
-        if task.done {
-            stats.pathfinding_duration.push_front(task.duration);
-            stats.pathfinding_duration.truncate(100);
-            stats.task_delay.push_front(task.delay);
-            stats.task_delay.truncate(100);
-            if let Some(path) = task.path.take() {
-                commands
-                    .entity(entity)
-                    .insert(Path { path: path.path })
-                    .remove::<FindingPath>();
-            } else {
-                if !mesh.is_in_mesh(transform.translation.xy()) {
-                    commands.entity(entity).despawn();
-                }
-
-                commands
-                    .entity(entity)
-                    .remove::<FindingPath>()
-                    .remove::<Target>();
-            }
-        }
-    });
+/// Orders given to a navigator by the player, it stops wandering around once it has some
+#[derive(Component, Default)]
+struct Orders {
+    /// Destinations to go to after the current one, in order
+    waypoints: VecDeque<Order>,
 }
 
-fn move_navigator(
-    mut query: Query<(Entity, &mut Transform, &mut Path, &Navigator)>,
-    time: Res<Time>,
+/// Sends the navigators that reached their destination to their next waypoint
+fn follow_orders(
     mut commands: Commands,
-    // mesh_q: Query<&TempNavmesh>,
+    mut idle: Query<
+        (Entity, &mut Orders),
+        (
+            Without<Path>,
+            Without<FindingPath>,
+            Without<Target>,
+            Without<FlowTarget>,
+            Without<FormationMember>,
+        ),
+    >,
 ) {
-    // let temp = mesh_q.single();
-    query.for_each_mut(|(entity, mut transform, mut path, navigator)| {
-        let next = path.path[0];
-        let toward = next - transform.translation.xy();
-        // TODO: compare this in mesh dimensions, not in display dimensions
-        if toward.length() < time.delta_seconds() * navigator.speed * 2.0 {
-            path.path.remove(0);
-            if path.path.is_empty() {
-                commands.entity(entity).remove::<Path>().remove::<Target>();
-            }
+    idle.for_each_mut(|(entity, mut orders)| {
+        if let Some(order) = orders.waypoints.pop_front() {
+            order.issue(&mut commands, entity);
         }
-        transform.translation +=
-            (toward.normalize() * time.delta_seconds() * navigator.speed).extend(0.0);
     });
 }
 
 fn display_path(
-    query: Query<(&Transform, &Path, &Navigator)>,
+    query: Query<(&Transform, &Path, &PathColor)>,
     mut lines: ResMut<DebugLines>,
     display_mode: Res<DisplayMode>,
 ) {
     if *display_mode == DisplayMode::Line {
-        query.for_each(|(transform, path, navigator)| {
+        query.for_each(|(transform, path, color)| {
             (1..path.path.len()).for_each(|i| {
                 lines.line_colored(
                     (path.path[i - 1]).extend(0f32),
                     (path.path[i]).extend(0f32),
                     0f32,
-                    navigator.color,
+                    color.0,
                 );
             });
             if let Some(next) = path.path.first() {
-                lines.line_colored(
-                    transform.translation,
-                    (*next).extend(0f32),
-                    0f32,
-                    navigator.color,
-                );
+                lines.line_colored(transform.translation, (*next).extend(0f32), 0f32, color.0);
             }
         });
     }
@@ -535,41 +656,63 @@ fn display_path(
 
 fn go_somewhere(
     query: Query<
-        Entity,
+        (Entity, &Transform, &NavmeshId),
         (
             With<Navigator>,
             Without<Path>,
             Without<FindingPath>,
             Without<Target>,
+            Without<FlowTarget>,
+            Without<Orders>,
         ),
     >,
-    mesh_q: Query<(&TempNavmesh, &Transform)>,
+    mesh_q: Query<&TempNavmesh>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
     mut commands: Commands,
 ) {
-    let (temp, transform) = mesh_q.single();
-    let mesh_size = &temp.dimensions;
     let rng = fastrand::Rng::new();
-    query.for_each(|navigator| {
-        let target = Vec2::new(
-            rng.f32() * mesh_size.x + transform.translation.x,
-            rng.f32() * mesh_size.y + transform.translation.y,
-        );
-        commands.entity(navigator).insert(Target { target });
+    query.for_each(|(navigator, transform, navmesh_id)| {
+        let temp = if let Ok(temp) = mesh_q.get(navmesh_id.0) {
+            temp
+        } else {
+            return;
+        };
+        // Only targets reachable from where the navigator is
+        let component = temp
+            .polygon_at(transform.translation.truncate())
+            .map(|polygon| temp.component(polygon));
+        let target = if let Some(target) = temp.random_point(&rng, None, component) {
+            target
+        } else {
+            return;
+        };
+        // Wandering off screen can wait for everything else
+        let priority = if is_on_screen(cameras.iter(), transform.translation.truncate()) {
+            PathPriority::OnScreen
+        } else {
+            PathPriority::Idle
+        };
+        commands
+            .entity(navigator)
+            .insert(Target { target, priority });
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 fn update_ui(
-    mut ui_query: Query<&mut Text>,
+    mut ui_query: Query<&mut Text, Without<EditorText>>,
     agents: Query<&Navigator>,
+    selected: Query<(), With<Selected>>,
     mut count: Local<usize>,
-    stats: Res<Stats>,
+    stats: Res<NavigationStats>,
     diagnostics: Res<Diagnostics>,
-    task_mode: Res<TaskMode>,
+    settings: Res<NavigationSettings>,
     display_mode: Res<DisplayMode>,
+    formation_settings: Res<FormationSettings>,
 ) {
     let new_count = agents.iter().len();
     let mut text = ui_query.single_mut();
-    text.sections[1].value = format!("{}\n", new_count);
+    text.sections[1].value = format!("{} ({} selected)\n", new_count, selected.iter().len());
     text.sections[3].value = format!(
         "{:.2}\n",
         diagnostics
@@ -591,26 +734,41 @@ fn update_ui(
             stats.task_delay.iter().sum::<f32>() / (stats.task_delay.len().max(1) as f32)
         )
     );
-    text.sections[9].value = format!("{:?}\n", *task_mode);
-    text.sections[11].value = format!(
-        "{}",
+    text.sections[9].value = format!("{} ({} running)\n", stats.queued, stats.in_flight);
+    text.sections[11].value = format!("{:.1}%\n", stats.cache_hit_rate() * 100.0);
+    text.sections[13].value = format!("{:?}\n", settings.task_mode);
+    text.sections[15].value = format!(
+        "{}\n",
         match *display_mode {
             DisplayMode::Line => "hide lines",
             DisplayMode::Nothing => "display lines",
         }
     );
+    text.sections[17].value = format!(
+        "{} avoidance\n",
+        if settings.avoidance.enabled {
+            "disable"
+        } else {
+            "enable"
+        }
+    );
+    text.sections[19].value = match formation_settings.shape {
+        Some(shape) => format!("formation: {:?}", shape),
+        None => "no formation".to_string(),
+    };
     *count = new_count;
 }
 
 fn mode_change(
     keyboard_input: Res<Input<KeyCode>>,
-    mut task_mode: ResMut<TaskMode>,
+    mut settings: ResMut<NavigationSettings>,
     mut display_mode: ResMut<DisplayMode>,
 ) {
     if keyboard_input.just_pressed(KeyCode::Space) {
-        match *task_mode {
-            TaskMode::Async => *task_mode = TaskMode::Blocking,
-            TaskMode::Blocking => *task_mode = TaskMode::Async,
+        match settings.task_mode {
+            TaskMode::Async => settings.task_mode = TaskMode::Blocking,
+            TaskMode::Blocking => settings.task_mode = TaskMode::Batched,
+            TaskMode::Batched => settings.task_mode = TaskMode::Async,
         }
     }
     if keyboard_input.just_pressed(KeyCode::L) {
@@ -619,4 +777,7 @@ fn mode_change(
             DisplayMode::Nothing => *display_mode = DisplayMode::Line,
         }
     }
+    if keyboard_input.just_pressed(KeyCode::O) {
+        settings.avoidance.enabled = !settings.avoidance.enabled;
+    }
 }
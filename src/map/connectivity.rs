@@ -0,0 +1,395 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::{info, Commands, Entity, Query, Res, ResMut, State},
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::Instant,
+};
+use bevy_ecs_tilemap::{
+    prelude::TilemapTileSize,
+    tiles::{TilePos, TileStorage, TileTexture},
+};
+use futures_lite::future;
+
+use crate::{navigation::TileCost, GameState};
+
+use super::{progress::GenerationProgress, texture_for_cost, MapTiles};
+
+/// Cost of tiles filled in to remove a region.
+const FILLED_COST: i8 = -1;
+/// Cost of the wall tiles carved to connect a region.
+const CORRIDOR_COST: i8 = 1;
+
+/// How walkable regions that are unreachable from the largest one are handled before the navmesh
+/// is generated. Tiles are connected if they share an edge.
+#[derive(Clone)]
+pub struct ConnectivitySettings {
+    pub enabled: bool,
+    /// Regions with fewer tiles than this are filled in
+    pub min_region_size: usize,
+    /// If larger regions are connected to the main one with corridors, or filled in too
+    pub carve_corridors: bool,
+}
+
+impl Default for ConnectivitySettings {
+    fn default() -> Self {
+        ConnectivitySettings {
+            enabled: true,
+            min_region_size: 10,
+            carve_corridors: true,
+        }
+    }
+}
+
+fn is_walkable(cost: i8) -> bool {
+    cost >= 1
+}
+
+fn neighbours(idx: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = (idx % width, idx / width);
+    [
+        (x > 0).then(|| idx - 1),
+        (x + 1 < width).then(|| idx + 1),
+        (y > 0).then(|| idx - width),
+        (y + 1 < height).then(|| idx + width),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Labels each walkable tile with the index of its region, and returns the tiles of each region.
+fn label_regions(tiles: &MapTiles) -> (Vec<Option<usize>>, Vec<Vec<usize>>) {
+    let (width, height) = (tiles.width as usize, tiles.height as usize);
+    let mut labels = vec![None; tiles.costs.len()];
+    let mut regions = Vec::new();
+
+    for start in 0..tiles.costs.len() {
+        if labels[start].is_some() || !is_walkable(tiles.costs[start]) {
+            continue;
+        }
+        let label = regions.len();
+        let mut region = vec![start];
+        labels[start] = Some(label);
+        let mut next = 0;
+        while next < region.len() {
+            let idx = region[next];
+            next += 1;
+            for neighbour in neighbours(idx, width, height) {
+                if labels[neighbour].is_none() && is_walkable(tiles.costs[neighbour]) {
+                    labels[neighbour] = Some(label);
+                    region.push(neighbour);
+                }
+            }
+        }
+        regions.push(region);
+    }
+
+    (labels, regions)
+}
+
+/// Finds the walls to carve to connect every region in `connect` to the tiles labeled `main`,
+/// crossing as few walls as possible for each. Walkable tiles are free to cross.
+///
+/// A single search spreads from the main region, each region is then connected by following
+/// the search back from its tile closest to it.
+fn find_corridors(
+    tiles: &MapTiles,
+    labels: &[Option<usize>],
+    regions: &[Vec<usize>],
+    main: usize,
+    connect: impl Iterator<Item = usize>,
+) -> Vec<usize> {
+    let (width, height) = (tiles.width as usize, tiles.height as usize);
+    let mut cost = vec![usize::MAX; tiles.costs.len()];
+    let mut previous = vec![usize::MAX; tiles.costs.len()];
+    let mut queue = VecDeque::new();
+    for idx in &regions[main] {
+        cost[*idx] = 0;
+        queue.push_back(*idx);
+    }
+
+    // 0-1 BFS: walkable tiles go in front of the queue, walls at the back
+    while let Some(idx) = queue.pop_front() {
+        for neighbour in neighbours(idx, width, height) {
+            let step = if is_walkable(tiles.costs[neighbour]) {
+                0
+            } else {
+                1
+            };
+            if cost[idx] + step < cost[neighbour] {
+                cost[neighbour] = cost[idx] + step;
+                previous[neighbour] = idx;
+                if step == 0 {
+                    queue.push_front(neighbour);
+                } else {
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    let mut corridors = Vec::new();
+    let mut connected = vec![false; regions.len()];
+    connected[main] = true;
+    for label in connect {
+        if connected[label] {
+            continue;
+        }
+        let mut current = match regions[label].iter().min_by_key(|idx| cost[**idx]) {
+            Some(closest) => *closest,
+            None => continue,
+        };
+        // Stops at a region that is already connected, or at a corridor carved for one
+        while previous[current] != usize::MAX {
+            // Filled in regions are walls too
+            match labels[current] {
+                _ if !is_walkable(tiles.costs[current]) => corridors.push(current),
+                Some(crossed) if connected[crossed] => break,
+                Some(crossed) => connected[crossed] = true,
+                None => {}
+            }
+            let next = previous[current];
+            previous[current] = usize::MAX;
+            current = next;
+        }
+    }
+    corridors
+}
+
+/// Makes every walkable tile reachable from the largest walkable region, by filling in small
+/// regions and connecting the others with corridors.
+pub fn ensure_connectivity(tiles: &mut MapTiles, settings: &ConnectivitySettings) {
+    let (labels, regions) = label_regions(tiles);
+    let main = match (0..regions.len()).max_by_key(|label| regions[*label].len()) {
+        Some(main) if regions.len() > 1 => main,
+        _ => return,
+    };
+
+    let is_kept = |label: usize| {
+        label == main
+            || (regions[label].len() >= settings.min_region_size && settings.carve_corridors)
+    };
+    for (label, region) in regions.iter().enumerate() {
+        if !is_kept(label) {
+            region
+                .iter()
+                .for_each(|idx| tiles.costs[*idx] = FILLED_COST);
+        }
+    }
+
+    if !settings.carve_corridors {
+        return;
+    }
+
+    // Corridors may go through other regions, which are connected too
+    let connect = (0..regions.len()).filter(|label| *label != main && is_kept(*label));
+    find_corridors(tiles, &labels, &regions, main, connect)
+        .iter()
+        .for_each(|idx| tiles.costs[*idx] = CORRIDOR_COST);
+}
+
+/// Tiles being connected on the async compute pool, by tilemap entity
+pub(crate) struct ConnectivityTasks(Vec<(Entity, Task<MapTiles>)>);
+
+pub(crate) fn connect_map_regions(
+    mut commands: Commands,
+    settings: Res<ConnectivitySettings>,
+    tilemap_q: Query<(Entity, &TileStorage, &TilemapTileSize)>,
+    tile_q: Query<(&TilePos, &TileCost, &TileTexture)>,
+    mut progress: ResMut<GenerationProgress>,
+) {
+    let reporter = progress.start_phase();
+    if !settings.enabled {
+        reporter.set(1.0);
+        commands.insert_resource(ConnectivityTasks(Vec::new()));
+        return;
+    }
+
+    let tasks = tilemap_q
+        .iter()
+        .map(|(entity, tile_storage, tile_size)| {
+            let mut tiles = MapTiles::from_tilemap(tile_storage, tile_size, |entity| {
+                let (tile_pos, tile_cost, tile_texture) = tile_q.get(entity).ok()?;
+                Some((*tile_pos, tile_cost.0, tile_texture.0))
+            });
+            let settings = settings.clone();
+            let reporter = reporter.clone();
+            let task = AsyncComputeTaskPool::get().spawn(async move {
+                let start_time = Instant::now();
+                ensure_connectivity(&mut tiles, &settings);
+                reporter.set(1.0);
+                let end_time = Instant::now();
+                info!("time to connect map regions: {:?}", end_time - start_time);
+                tiles
+            });
+            (entity, task)
+        })
+        .collect();
+    commands.insert_resource(ConnectivityTasks(tasks));
+}
+
+pub(crate) fn poll_map_connectivity(
+    mut commands: Commands,
+    tasks: Option<ResMut<ConnectivityTasks>>,
+    tilemap_q: Query<&TileStorage>,
+    mut tile_q: Query<(&TilePos, &mut TileCost, &mut TileTexture)>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let mut tasks = match tasks {
+        Some(tasks) => tasks,
+        None => return,
+    };
+    tasks.0.retain_mut(|(entity, task)| {
+        let tiles = match future::block_on(future::poll_once(task)) {
+            Some(tiles) => tiles,
+            None => return true,
+        };
+        // The map was despawned while the task was running
+        let tile_storage = match tilemap_q.get(*entity) {
+            Ok(tile_storage) => tile_storage,
+            Err(_) => return false,
+        };
+        let mut changed = 0;
+        for tile_entity in tile_storage.iter().flatten() {
+            if let Ok((tile_pos, mut tile_cost, mut tile_texture)) = tile_q.get_mut(*tile_entity) {
+                let cost = tiles.cost(tile_pos.x, tile_pos.y);
+                if tile_cost.0 != cost {
+                    tile_cost.0 = cost;
+                    tile_texture.0 = texture_for_cost(cost);
+                    changed += 1;
+                }
+            }
+        }
+        info!("connectivity post-processing changed {} tiles", changed);
+        false
+    });
+    if tasks.0.is_empty() {
+        commands.remove_resource::<ConnectivityTasks>();
+        state
+            .set(GameState::NavMeshGeneration)
+            .expect("Unable to transition from map post processing state to navmesh gen state");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tiles from rows of `#` for walls and `.` for walkable tiles, the first row at the top
+    fn tiles(rows: &[&str]) -> MapTiles {
+        let costs = rows
+            .iter()
+            .rev()
+            .flat_map(|row| row.chars().map(|c| if c == '.' { 1 } else { -1 }))
+            .collect();
+        MapTiles::from_costs(rows[0].len() as u32, rows.len() as u32, costs)
+    }
+
+    fn settings(min_region_size: usize, carve_corridors: bool) -> ConnectivitySettings {
+        ConnectivitySettings {
+            enabled: true,
+            min_region_size,
+            carve_corridors,
+        }
+    }
+
+    fn region_count(tiles: &MapTiles) -> usize {
+        label_regions(tiles).1.len()
+    }
+
+    #[test]
+    fn labels_regions_sharing_an_edge() {
+        let map = tiles(&[
+            "..#.", //
+            "#.#.", //
+            "#.##", //
+            ".#..",
+        ]);
+        let (labels, regions) = label_regions(&map);
+
+        let mut sizes: Vec<usize> = regions.iter().map(Vec::len).collect();
+        sizes.sort_unstable();
+        // Diagonal tiles aren't connected
+        assert_eq!(sizes, vec![1, 2, 2, 4]);
+        assert_eq!(labels.iter().filter(|label| label.is_none()).count(), 7);
+        for (label, region) in regions.iter().enumerate() {
+            assert!(region.iter().all(|idx| labels[*idx] == Some(label)));
+        }
+    }
+
+    #[test]
+    fn fills_small_regions() {
+        let mut map = tiles(&[
+            ".....", //
+            ".....", //
+            "#####", //
+            "..#.#",
+        ]);
+        ensure_connectivity(&mut map, &settings(3, true));
+
+        // The bottom row is all walls, without a corridor
+        assert_eq!(region_count(&map), 1);
+        assert!((0..5).all(|x| !is_walkable(map.cost(x, 0))));
+        assert_eq!(
+            map.costs.iter().filter(|cost| is_walkable(**cost)).count(),
+            10
+        );
+    }
+
+    #[test]
+    fn fills_every_other_region_without_corridors() {
+        let mut map = tiles(&[
+            "....#...", //
+            "....#...",
+        ]);
+        ensure_connectivity(&mut map, &settings(1, false));
+
+        assert_eq!(region_count(&map), 1);
+        assert_eq!(
+            map.costs.iter().filter(|cost| is_walkable(**cost)).count(),
+            8
+        );
+    }
+
+    #[test]
+    fn carves_the_shortest_corridors() {
+        let mut map = tiles(&[
+            "....##...", //
+            "....###..", //
+            "....####.",
+        ]);
+        ensure_connectivity(&mut map, &settings(1, true));
+
+        assert_eq!(region_count(&map), 1);
+        // Only the two walls of the top row are carved
+        assert_eq!(map.cost(4, 2), CORRIDOR_COST);
+        assert_eq!(map.cost(5, 2), CORRIDOR_COST);
+        assert_eq!(
+            map.costs
+                .iter()
+                .filter(|cost| **cost == CORRIDOR_COST)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn connects_regions_through_each_other() {
+        let mut map = tiles(&[
+            "....#..#..", //
+            "....#..#..", //
+            "....#..#..",
+        ]);
+        ensure_connectivity(&mut map, &settings(1, true));
+
+        // The far region goes through the middle one, one wall each
+        assert_eq!(region_count(&map), 1);
+        assert_eq!(
+            map.costs
+                .iter()
+                .filter(|cost| **cost == CORRIDOR_COST)
+                .count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::{HashMap, HashSet},
+};
+use bevy_ecs_tilemap::{
+    prelude::{TilemapGridSize, TilemapType},
+    tiles::{TilePos, TileStorage, TileTexture},
+};
+use futures_lite::future;
+
+use crate::{
+    actions::cursor_world_position,
+    loading::FontAssets,
+    navigation::{NavmeshInput, TempNavmesh, TileCost},
+};
+
+use super::texture_for_cost;
+
+const MIN_COST: i8 = -2;
+const MAX_COST: i8 = 7;
+const MAX_BRUSH_SIZE: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorTool {
+    Brush,
+    Rectangle,
+    FloodFill,
+}
+
+impl EditorTool {
+    fn next(self) -> Self {
+        match self {
+            EditorTool::Brush => EditorTool::Rectangle,
+            EditorTool::Rectangle => EditorTool::FloodFill,
+            EditorTool::FloodFill => EditorTool::Brush,
+        }
+    }
+}
+
+/// Painting tile costs with the mouse while playing.
+///
+/// e - toggle the editor, tab - next tool, [ and ] - brush size, - and = - tile cost
+pub struct MapEditor {
+    pub enabled: bool,
+    pub tool: EditorTool,
+    /// Width of the square brush, in tiles
+    pub brush_size: u32,
+    /// Cost of the painted tiles
+    pub cost: i8,
+}
+
+impl Default for MapEditor {
+    fn default() -> Self {
+        MapEditor {
+            enabled: false,
+            tool: EditorTool::Brush,
+            brush_size: 1,
+            cost: -1,
+        }
+    }
+}
+
+/// Sent at the end of each editor stroke, the navmesh of the tilemap is regenerated.
+pub struct MapEdited {
+    pub tilemap: Entity,
+}
+
+#[derive(Component)]
+pub(crate) struct EditorText;
+
+pub(crate) fn setup_editor_text(mut commands: Commands, font_assets: Res<FontAssets>) {
+    commands
+        .spawn_bundle(TextBundle {
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    font: font_assets.fira_sans.clone(),
+                    font_size: 15.0,
+                    color: Color::WHITE,
+                },
+            ),
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    bottom: Val::Px(5.0),
+                    left: Val::Px(5.0),
+                    ..default()
+                },
+                ..default()
+            },
+            ..default()
+        })
+        .insert(EditorText);
+}
+
+pub(crate) fn editor_input(keyboard_input: Res<Input<KeyCode>>, mut editor: ResMut<MapEditor>) {
+    if keyboard_input.just_pressed(KeyCode::E) {
+        editor.enabled = !editor.enabled;
+    }
+    if !editor.enabled {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Tab) {
+        editor.tool = editor.tool.next();
+    }
+    if keyboard_input.just_pressed(KeyCode::LBracket) {
+        editor.brush_size = (editor.brush_size - 1).max(1);
+    }
+    if keyboard_input.just_pressed(KeyCode::RBracket) {
+        editor.brush_size = (editor.brush_size + 1).min(MAX_BRUSH_SIZE);
+    }
+    if keyboard_input.just_pressed(KeyCode::Minus) {
+        editor.cost = (editor.cost - 1).max(MIN_COST);
+    }
+    if keyboard_input.just_pressed(KeyCode::Equals) {
+        editor.cost = (editor.cost + 1).min(MAX_COST);
+    }
+}
+
+pub(crate) fn update_editor_text(
+    editor: Res<MapEditor>,
+    mut text_q: Query<&mut Text, With<EditorText>>,
+    new_text_q: Query<(), Added<EditorText>>,
+) {
+    // The text is spawned again each time a new map is played
+    if !editor.is_changed() && new_text_q.is_empty() {
+        return;
+    }
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = if editor.enabled {
+            format!(
+                "editor - tool: {:?} (tab), brush: {} ([ ]), cost: {} (- =), e to exit",
+                editor.tool, editor.brush_size, editor.cost
+            )
+        } else {
+            "e - map editor, r - new map".to_string()
+        };
+    }
+}
+
+/// Tile under a world position, if it's in the tilemap
+fn tile_at(
+    world_pos: Vec2,
+    tile_storage: &TileStorage,
+    grid_size: &TilemapGridSize,
+    transform: &Transform,
+) -> Option<TilePos> {
+    // Tile centers are at `tile_pos * grid_size` from the tilemap origin
+    let local =
+        (world_pos - transform.translation.truncate()) / Vec2::new(grid_size.x, grid_size.y) + 0.5;
+    if local.x < 0.0
+        || local.y < 0.0
+        || local.x >= tile_storage.size.x as f32
+        || local.y >= tile_storage.size.y as f32
+    {
+        return None;
+    }
+    Some(TilePos {
+        x: local.x as u32,
+        y: local.y as u32,
+    })
+}
+
+/// Returns `true` if the tile had another cost
+fn paint_tile(
+    tile_storage: &TileStorage,
+    tile_pos: &TilePos,
+    cost: i8,
+    tile_q: &mut Query<(&mut TileCost, &mut TileTexture)>,
+) -> bool {
+    if let Some(tile_entity) = tile_storage.get(tile_pos) {
+        if let Ok((mut tile_cost, mut tile_texture)) = tile_q.get_mut(tile_entity) {
+            if tile_cost.0 != cost {
+                tile_cost.0 = cost;
+                tile_texture.0 = texture_for_cost(cost);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Paints every tile in the rectangle between two corners, inclusive. Returns `true` if a tile
+/// changed.
+fn paint_rectangle(
+    tile_storage: &TileStorage,
+    from: (i64, i64),
+    to: (i64, i64),
+    cost: i8,
+    tile_q: &mut Query<(&mut TileCost, &mut TileTexture)>,
+) -> bool {
+    let max_x = tile_storage.size.x as i64 - 1;
+    let max_y = tile_storage.size.y as i64 - 1;
+    let mut changed = false;
+    for x in from.0.min(to.0).max(0)..=from.0.max(to.0).min(max_x) {
+        for y in from.1.min(to.1).max(0)..=from.1.max(to.1).min(max_y) {
+            let tile_pos = TilePos {
+                x: x as u32,
+                y: y as u32,
+            };
+            changed |= paint_tile(tile_storage, &tile_pos, cost, tile_q);
+        }
+    }
+    changed
+}
+
+fn cost_at(
+    tile_storage: &TileStorage,
+    tile_pos: &TilePos,
+    tile_q: &Query<(&mut TileCost, &mut TileTexture)>,
+) -> Option<i8> {
+    tile_storage
+        .get(tile_pos)
+        .and_then(|tile_entity| tile_q.get(tile_entity).ok())
+        .map(|(tile_cost, _)| tile_cost.0)
+}
+
+/// Paints the tiles connected to `start` that have the same cost. Returns `true` if a tile
+/// changed.
+fn flood_fill(
+    tile_storage: &TileStorage,
+    start: TilePos,
+    cost: i8,
+    tile_q: &mut Query<(&mut TileCost, &mut TileTexture)>,
+) -> bool {
+    let replaced = match cost_at(tile_storage, &start, tile_q) {
+        Some(replaced) if replaced != cost => replaced,
+        _ => return false,
+    };
+
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([start]);
+    visited.insert((start.x, start.y));
+    while let Some(tile_pos) = queue.pop_front() {
+        paint_tile(tile_storage, &tile_pos, cost, tile_q);
+        let (x, y) = (tile_pos.x as i64, tile_pos.y as i64);
+        for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+            if nx < 0
+                || ny < 0
+                || nx >= tile_storage.size.x as i64
+                || ny >= tile_storage.size.y as i64
+                || !visited.insert((nx as u32, ny as u32))
+            {
+                continue;
+            }
+            let neighbour = TilePos {
+                x: nx as u32,
+                y: ny as u32,
+            };
+            if cost_at(tile_storage, &neighbour, tile_q) == Some(replaced) {
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    true
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn paint_tiles(
+    editor: Res<MapEditor>,
+    buttons: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    tilemap_q: Query<(Entity, &TileStorage, &TilemapGridSize, &Transform)>,
+    mut tile_q: Query<(&mut TileCost, &mut TileTexture)>,
+    mut edited: EventWriter<MapEdited>,
+    mut rectangle_start: Local<Option<TilePos>>,
+    mut stroke_changed: Local<HashSet<Entity>>,
+) {
+    if !editor.enabled {
+        *rectangle_start = None;
+        stroke_changed.clear();
+        return;
+    }
+    let window = windows.get_primary().unwrap();
+    let (camera, camera_transform) = q_camera.single();
+    let world_pos = match cursor_world_position(window, camera, camera_transform) {
+        Some(world_pos) => world_pos,
+        None => return,
+    };
+
+    for (tilemap, tile_storage, grid_size, transform) in tilemap_q.iter() {
+        let tile_pos = tile_at(world_pos, tile_storage, grid_size, transform);
+
+        match editor.tool {
+            EditorTool::Brush => {
+                if let Some(tile_pos) = tile_pos.filter(|_| buttons.pressed(MouseButton::Left)) {
+                    let (x, y) = (tile_pos.x as i64, tile_pos.y as i64);
+                    // Centered on the cursor, odd sizes are symmetric
+                    let before = (editor.brush_size as i64 - 1) / 2;
+                    let after = editor.brush_size as i64 - 1 - before;
+                    if paint_rectangle(
+                        tile_storage,
+                        (x - before, y - before),
+                        (x + after, y + after),
+                        editor.cost,
+                        &mut tile_q,
+                    ) {
+                        stroke_changed.insert(tilemap);
+                    }
+                }
+                // The navmesh is rebuilt once per stroke, for the tilemaps it changed
+                if buttons.just_released(MouseButton::Left) && stroke_changed.remove(&tilemap) {
+                    edited.send(MapEdited { tilemap });
+                }
+            }
+            EditorTool::Rectangle => {
+                if buttons.just_pressed(MouseButton::Left) {
+                    *rectangle_start = tile_pos;
+                }
+                if buttons.just_released(MouseButton::Left) {
+                    if let (Some(start), Some(end)) = (rectangle_start.take(), tile_pos) {
+                        if paint_rectangle(
+                            tile_storage,
+                            (start.x as i64, start.y as i64),
+                            (end.x as i64, end.y as i64),
+                            editor.cost,
+                            &mut tile_q,
+                        ) {
+                            edited.send(MapEdited { tilemap });
+                        }
+                    }
+                }
+            }
+            EditorTool::FloodFill => {
+                if let Some(tile_pos) = tile_pos.filter(|_| buttons.just_pressed(MouseButton::Left))
+                {
+                    if flood_fill(tile_storage, tile_pos, editor.cost, &mut tile_q) {
+                        edited.send(MapEdited { tilemap });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rebuilds the navmesh of edited tilemaps on the async compute pool, a newer edit replaces the
+/// rebuild still running for the same tilemap.
+pub(crate) fn rebuild_edited_navmesh(
+    mut commands: Commands,
+    mut edited: EventReader<MapEdited>,
+    tilemap_query: Query<(&TilemapType, &TilemapGridSize, &TileStorage, &Transform)>,
+    tile_query: Query<(&TilePos, &TileCost)>,
+    mut tasks: Local<HashMap<Entity, Task<TempNavmesh>>>,
+) {
+    let tilemaps: HashSet<Entity> = edited.iter().map(|edited| edited.tilemap).collect();
+    for tilemap in tilemaps {
+        if let Ok((map_type, grid_size, tilemap_storage, transform)) = tilemap_query.get(tilemap) {
+            let input = NavmeshInput::from_tilemap(
+                map_type,
+                grid_size,
+                tilemap_storage,
+                transform,
+                &tile_query,
+            );
+            let task = AsyncComputeTaskPool::get().spawn(async move { input.build(|_| {}) });
+            // Dropping the previous task cancels it
+            tasks.insert(tilemap, task);
+        }
+    }
+
+    tasks.retain(
+        |tilemap, task| match future::block_on(future::poll_once(task)) {
+            Some(navmesh) => {
+                // The map may have been despawned for a new one since
+                if tilemap_query.get(*tilemap).is_ok() {
+                    commands.entity(*tilemap).insert(navmesh);
+                }
+                false
+            }
+            None => true,
+        },
+    );
+}
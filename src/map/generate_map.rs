@@ -1,5 +1,6 @@
 use bevy::{
-    prelude::{info, Commands, Res},
+    prelude::{error, info, Commands, Res, ResMut, State},
+    tasks::{AsyncComputeTaskPool, Task},
     utils::Instant,
 };
 use bevy_ecs_tilemap::{
@@ -9,46 +10,156 @@ use bevy_ecs_tilemap::{
     tiles::{TileBundle, TilePos, TileStorage, TileTexture},
     TilemapBundle,
 };
+use futures_lite::future;
 
-use crate::loading::TextureAssets;
+use crate::{loading::TextureAssets, navigation::TileCost, GameState};
 
-use super::{MapDimensions, TileCost};
+use super::{
+    image_map::{load_image_map, MapPalette},
+    map_file::load_map_file,
+    progress::{GenerationProgress, ProgressReporter},
+    tiled_map::{load_tiled_map, TiledSettings},
+    MapDimensions, MapSeed, MapSource, MapTiles,
+};
+
+/// The map being loaded or generated on the async compute pool
+pub(crate) struct MapGenerationTask(Task<MapTiles>);
 
-pub(crate) fn generate_map(
+pub(crate) fn start_map_generation(
     mut commands: Commands,
-    textures: Res<TextureAssets>,
     map_dimensions: Res<MapDimensions>,
+    map_source: Res<MapSource>,
+    map_seed: Res<MapSeed>,
+    palette: Res<MapPalette>,
+    tiled_settings: Res<TiledSettings>,
+    mut progress: ResMut<GenerationProgress>,
+) {
+    let reporter = progress.start_phase();
+    let (width, height) = (map_dimensions.width, map_dimensions.height);
+    let seed = map_seed.0;
+    let map_source = map_source.clone();
+    let palette = palette.clone();
+    let tiled_settings = tiled_settings.clone();
+
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        let start_time = Instant::now();
+        let tiles = load_tiles(
+            &map_source,
+            (width, height),
+            seed,
+            &palette,
+            &tiled_settings,
+            &reporter,
+        );
+        let end_time = Instant::now();
+        info!("time to generate map: {:?}", end_time - start_time);
+        tiles
+    });
+    commands.insert_resource(MapGenerationTask(task));
+}
+
+pub(crate) fn poll_map_generation(
+    mut commands: Commands,
+    textures: Res<TextureAssets>,
+    mut map_dimensions: ResMut<MapDimensions>,
+    task: Option<ResMut<MapGenerationTask>>,
+    mut state: ResMut<State<GameState>>,
 ) {
-    let start_time = Instant::now();
+    let mut task = match task {
+        Some(task) => task,
+        None => return,
+    };
+    if let Some(tiles) = future::block_on(future::poll_once(&mut task.0)) {
+        map_dimensions.width = tiles.width;
+        map_dimensions.height = tiles.height;
+
+        spawn_tilemap(&mut commands, &textures, &tiles);
+        commands.remove_resource::<MapGenerationTask>();
+        state
+            .set(GameState::MapPostProcessing)
+            .expect("Unable to transition from map gen state to map post processing state");
+    }
+}
+
+/// Loads the map from its source, generating a random one if it can't be loaded
+fn load_tiles(
+    map_source: &MapSource,
+    size: (u32, u32),
+    seed: u64,
+    palette: &MapPalette,
+    tiled_settings: &TiledSettings,
+    reporter: &ProgressReporter,
+) -> MapTiles {
+    match map_source {
+        MapSource::Generated => random_tiles(size, seed, reporter),
+        MapSource::Image(path) => match load_image_map(path, palette) {
+            Ok(tiles) => tiles,
+            Err(err) => {
+                error!("unable to load map image {}: {}", path.display(), err);
+                random_tiles(size, seed, reporter)
+            }
+        },
+        MapSource::Tiled(path) => match load_tiled_map(path, tiled_settings) {
+            Ok(tiles) => tiles,
+            Err(err) => {
+                error!("unable to load tiled map {}: {}", path.display(), err);
+                random_tiles(size, seed, reporter)
+            }
+        },
+        MapSource::File(path) => match load_map_file(path) {
+            Ok(tiles) => tiles,
+            Err(err) => {
+                error!("unable to load map file {}: {}", path.display(), err);
+                random_tiles(size, seed, reporter)
+            }
+        },
+    }
+}
+
+fn random_tiles((width, height): (u32, u32), seed: u64, reporter: &ProgressReporter) -> MapTiles {
+    let rng = fastrand::Rng::with_seed(seed);
+
+    let mut costs = vec![0; (width * height) as usize];
+    for x in 0..width {
+        for y in 0..height {
+            costs[(y * width + x) as usize] = rng.i8(-2..8);
+        }
+        reporter.set((x + 1) as f32 / width as f32);
+    }
+
+    MapTiles::from_costs(width, height, costs)
+}
 
+/// Spawns a tilemap entity and one entity per tile with its [`TileCost`].
+pub(crate) fn spawn_tilemap(commands: &mut Commands, textures: &TextureAssets, tiles: &MapTiles) {
     let tilemap_size = TilemapSize {
-        x: map_dimensions.width,
-        y: map_dimensions.height,
+        x: tiles.width,
+        y: tiles.height,
     };
     let mut tile_storage = TileStorage::empty(tilemap_size);
     let tilemap_entity = commands.spawn().id();
-    let rng = fastrand::Rng::with_seed(1);
 
-    for x in 0..map_dimensions.width {
-        for y in 0..map_dimensions.height {
+    for x in 0..tiles.width {
+        for y in 0..tiles.height {
             let tile_pos = TilePos { x, y };
-            let tile_cost = rng.i8(-2..8);
-            let texture = if tile_cost < 1 { 1 } else { 0 };
             let tile_entity = commands
                 .spawn()
                 .insert_bundle(TileBundle {
                     position: tile_pos,
                     tilemap_id: TilemapId(tilemap_entity),
-                    texture: TileTexture(texture),
+                    texture: TileTexture(tiles.texture(x, y)),
                     ..Default::default()
                 })
-                .insert(TileCost(tile_cost))
+                .insert(TileCost(tiles.cost(x, y)))
                 .id();
             tile_storage.set(&tile_pos, tile_entity);
         }
     }
 
-    let tile_size = TilemapTileSize { x: 16.0, y: 16.0 };
+    let tile_size = TilemapTileSize {
+        x: tiles.tile_size.x,
+        y: tiles.tile_size.y,
+    };
     let grid_size = tile_size.into();
 
     commands
@@ -62,7 +173,4 @@ pub(crate) fn generate_map(
             transform: get_tilemap_center_transform(&tilemap_size, &grid_size, 0.0),
             ..Default::default()
         });
-
-    let end_time = Instant::now();
-    info!("time to generate map: {:?}", end_time - start_time);
 }
@@ -0,0 +1,140 @@
+use std::path::Path;
+
+use image::{ImageError, RgbImage};
+
+use super::MapTiles;
+
+#[derive(Clone)]
+pub struct PaletteEntry {
+    pub color: [u8; 3],
+    pub cost: i8,
+}
+
+/// Maps pixel colours to tile costs when loading a map from an image.
+/// Colours that aren't in the palette use the closest entry.
+#[derive(Clone)]
+pub struct MapPalette {
+    pub entries: Vec<PaletteEntry>,
+}
+
+impl Default for MapPalette {
+    fn default() -> Self {
+        MapPalette {
+            entries: vec![
+                // walls
+                PaletteEntry {
+                    color: [0, 0, 0],
+                    cost: -1,
+                },
+                // water
+                PaletteEntry {
+                    color: [0, 0, 255],
+                    cost: 0,
+                },
+                // floor
+                PaletteEntry {
+                    color: [255, 255, 255],
+                    cost: 1,
+                },
+                // grass
+                PaletteEntry {
+                    color: [0, 255, 0],
+                    cost: 3,
+                },
+                // mud
+                PaletteEntry {
+                    color: [128, 64, 0],
+                    cost: 6,
+                },
+            ],
+        }
+    }
+}
+
+impl MapPalette {
+    pub fn cost(&self, color: [u8; 3]) -> i8 {
+        self.entries
+            .iter()
+            .min_by_key(|entry| {
+                entry
+                    .color
+                    .iter()
+                    .zip(color.iter())
+                    .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                    .sum::<i32>()
+            })
+            .map(|entry| entry.cost)
+            .unwrap_or_default()
+    }
+}
+
+/// Loads a map from an image, one pixel per tile. The top row of the image is the top of the map.
+pub fn load_image_map(
+    path: impl AsRef<Path>,
+    palette: &MapPalette,
+) -> Result<MapTiles, ImageError> {
+    Ok(tiles_from_image(&image::open(path)?.into_rgb8(), palette))
+}
+
+fn tiles_from_image(image: &RgbImage, palette: &MapPalette) -> MapTiles {
+    let (width, height) = image.dimensions();
+
+    let mut costs = vec![0; (width * height) as usize];
+    for (x, row, pixel) in image.enumerate_pixels() {
+        // tilemap y goes up, image y goes down
+        let y = height - 1 - row;
+        costs[(y * width + x) as usize] = palette.cost(pixel.0);
+    }
+
+    MapTiles::from_costs(width, height, costs)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn palette_matches_exact_colours() {
+        let palette = MapPalette::default();
+        for entry in palette.entries.iter() {
+            assert_eq!(palette.cost(entry.color), entry.cost);
+        }
+    }
+
+    #[test]
+    fn palette_matches_the_closest_colour() {
+        let palette = MapPalette::default();
+        assert_eq!(palette.cost([20, 10, 30]), -1);
+        assert_eq!(palette.cost([40, 200, 60]), 3);
+        assert_eq!(palette.cost([230, 240, 250]), 1);
+        assert_eq!(palette.cost([140, 70, 10]), 6);
+    }
+
+    #[test]
+    fn empty_palette_uses_the_default_cost() {
+        let palette = MapPalette {
+            entries: Vec::new(),
+        };
+        assert_eq!(palette.cost([255, 0, 0]), 0);
+    }
+
+    #[test]
+    fn one_tile_per_pixel_from_the_bottom_row() {
+        // 3 pixels wide and 2 high, the top row is walls, water and mud
+        let image = RgbImage::from_fn(3, 2, |x, y| match (x, y) {
+            (0, 0) => Rgb([0, 0, 0]),
+            (1, 0) => Rgb([0, 0, 255]),
+            (2, 0) => Rgb([128, 64, 0]),
+            (0, 1) => Rgb([0, 255, 0]),
+            _ => Rgb([255, 255, 255]),
+        });
+        let tiles = tiles_from_image(&image, &MapPalette::default());
+
+        assert_eq!((tiles.width, tiles.height), (3, 2));
+        assert_eq!(tiles.costs, vec![3, 1, 1, -1, 0, 6]);
+        assert_eq!(tiles.cost(0, 1), -1);
+        assert_eq!(tiles.cost(0, 0), 3);
+    }
+}
@@ -0,0 +1,319 @@
+//! Human-readable map files, in RON or JSON depending on the extension.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::{error, info, Input, KeyCode, Query, Res, Vec2};
+use bevy_ecs_tilemap::{
+    prelude::TilemapTileSize,
+    tiles::{TilePos, TileStorage, TileTexture},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::navigation::TileCost;
+
+use super::{extension, MapTiles, TILE_TEXTURE_COUNT};
+
+#[derive(Debug)]
+pub enum MapFileError {
+    Io(io::Error),
+    Ron(String),
+    Json(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MapFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapFileError::Io(err) => write!(f, "io error: {}", err),
+            MapFileError::Ron(err) => write!(f, "ron error: {}", err),
+            MapFileError::Json(err) => write!(f, "json error: {}", err),
+            MapFileError::Invalid(message) => write!(f, "invalid map: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MapFileError {}
+
+impl From<io::Error> for MapFileError {
+    fn from(err: io::Error) -> Self {
+        MapFileError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for MapFileError {
+    fn from(err: serde_json::Error) -> Self {
+        MapFileError::Json(err)
+    }
+}
+
+/// The serialized form of [`MapTiles`]. Rows go from the top of the map to the bottom so that
+/// the file looks like the map.
+#[derive(Serialize, Deserialize)]
+pub struct MapFile {
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: (f32, f32),
+    pub costs: Vec<Vec<i8>>,
+    pub textures: Vec<Vec<u32>>,
+}
+
+/// Splits bottom to top tiles into top to bottom rows
+fn to_rows<T: Clone>(values: &[T], width: u32) -> Vec<Vec<T>> {
+    if width == 0 {
+        return Vec::new();
+    }
+    values
+        .chunks(width as usize)
+        .rev()
+        .map(|row| row.to_vec())
+        .collect()
+}
+
+/// Tiles are built around their center with integer corners, so their half size must be whole
+pub(super) fn is_valid_tile_size(size: f32) -> bool {
+    size.is_finite() && size > 0.0 && (size / 2.0).fract() == 0.0
+}
+
+fn has_size<T>(rows: &[Vec<T>], width: u32, height: u32) -> bool {
+    rows.len() == height as usize && rows.iter().all(|row| row.len() == width as usize)
+}
+
+impl From<&MapTiles> for MapFile {
+    fn from(tiles: &MapTiles) -> Self {
+        MapFile {
+            width: tiles.width,
+            height: tiles.height,
+            tile_size: (tiles.tile_size.x, tiles.tile_size.y),
+            costs: to_rows(&tiles.costs, tiles.width),
+            textures: to_rows(&tiles.textures, tiles.width),
+        }
+    }
+}
+
+impl TryFrom<MapFile> for MapTiles {
+    type Error = MapFileError;
+
+    fn try_from(file: MapFile) -> Result<Self, Self::Error> {
+        if file.width == 0 || file.height == 0 {
+            return Err(MapFileError::Invalid(format!(
+                "the map is {}x{} tiles, it can't be empty",
+                file.width, file.height
+            )));
+        }
+        if !is_valid_tile_size(file.tile_size.0) || !is_valid_tile_size(file.tile_size.1) {
+            return Err(MapFileError::Invalid(format!(
+                "tile size {:?} must be positive even numbers",
+                file.tile_size
+            )));
+        }
+        if !has_size(&file.costs, file.width, file.height)
+            || !has_size(&file.textures, file.width, file.height)
+        {
+            return Err(MapFileError::Invalid(format!(
+                "costs and textures must be {} rows of {} tiles",
+                file.height, file.width
+            )));
+        }
+        if let Some(texture) = file
+            .textures
+            .iter()
+            .flatten()
+            .find(|texture| **texture >= TILE_TEXTURE_COUNT)
+        {
+            return Err(MapFileError::Invalid(format!(
+                "texture {} is not in the tiles texture, which has {} textures",
+                texture, TILE_TEXTURE_COUNT
+            )));
+        }
+        Ok(MapTiles {
+            width: file.width,
+            height: file.height,
+            tile_size: Vec2::new(file.tile_size.0, file.tile_size.1),
+            costs: file.costs.into_iter().rev().flatten().collect(),
+            textures: file.textures.into_iter().rev().flatten().collect(),
+        })
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    extension(path).as_deref() == Some("json")
+}
+
+pub fn load_map_file(path: impl AsRef<Path>) -> Result<MapTiles, MapFileError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let file: MapFile = if is_json(path) {
+        serde_json::from_str(&contents)?
+    } else {
+        ron::from_str(&contents).map_err(|err| MapFileError::Ron(err.to_string()))?
+    };
+    file.try_into()
+}
+
+pub fn save_map_file(path: impl AsRef<Path>, tiles: &MapTiles) -> Result<(), MapFileError> {
+    let path = path.as_ref();
+    let file = MapFile::from(tiles);
+    let contents = if is_json(path) {
+        serde_json::to_string(&file)?
+    } else {
+        // Keep each row on a single line
+        ron::ser::to_string_pretty(&file, ron::ser::PrettyConfig::new().depth_limit(2))
+            .map_err(|err| MapFileError::Ron(err.to_string()))?
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Saves each tilemap to `map_<timestamp>_<tilemap>.ron` in the working directory when pressing
+/// F5.
+pub(crate) fn save_map(
+    keyboard_input: Res<Input<KeyCode>>,
+    tilemap_q: Query<(&TileStorage, &TilemapTileSize)>,
+    tile_q: Query<(&TilePos, &TileCost, &TileTexture)>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F5) {
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    for (index, (tile_storage, tile_size)) in tilemap_q.iter().enumerate() {
+        let tiles = MapTiles::from_tilemap(tile_storage, tile_size, |entity| {
+            let (tile_pos, tile_cost, tile_texture) = tile_q.get(entity).ok()?;
+            Some((*tile_pos, tile_cost.0, tile_texture.0))
+        });
+
+        let path = PathBuf::from(format!("map_{}_{}.ron", timestamp, index));
+        match save_map_file(&path, &tiles) {
+            Ok(()) => info!("saved map to {}", path.display()),
+            Err(err) => error!("unable to save map to {}: {}", path.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3 by 2 map, with the costs and textures of the bottom row first
+    fn tiles() -> MapTiles {
+        MapTiles {
+            width: 3,
+            height: 2,
+            tile_size: Vec2::new(16.0, 32.0),
+            costs: vec![1, 2, 3, -1, 5, 6],
+            textures: vec![0, 1, 2, 3, 4, 5],
+        }
+    }
+
+    fn assert_same_tiles(left: &MapTiles, right: &MapTiles) {
+        assert_eq!((left.width, left.height), (right.width, right.height));
+        assert_eq!(left.tile_size, right.tile_size);
+        assert_eq!(left.costs, right.costs);
+        assert_eq!(left.textures, right.textures);
+    }
+
+    fn file() -> MapFile {
+        MapFile::from(&tiles())
+    }
+
+    #[test]
+    fn rows_go_from_top_to_bottom() {
+        let file = file();
+
+        assert_eq!(file.costs, vec![vec![-1, 5, 6], vec![1, 2, 3]]);
+        assert_eq!(file.textures, vec![vec![3, 4, 5], vec![0, 1, 2]]);
+        assert_eq!(file.tile_size, (16.0, 32.0));
+    }
+
+    #[test]
+    fn converts_back_to_the_same_tiles() {
+        let tiles = MapTiles::try_from(file()).unwrap();
+
+        assert_same_tiles(&tiles, &self::tiles());
+    }
+
+    #[test]
+    fn rejects_rows_of_the_wrong_size() {
+        let mut short_row = file();
+        short_row.costs[1].pop();
+        let mut missing_row = file();
+        missing_row.textures.pop();
+        let mut wrong_height = file();
+        wrong_height.height = 3;
+
+        for file in [short_row, missing_row, wrong_height] {
+            assert!(matches!(
+                MapTiles::try_from(file),
+                Err(MapFileError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_empty_maps_and_bad_tile_sizes() {
+        let empty = MapFile {
+            width: 0,
+            height: 0,
+            tile_size: (16.0, 16.0),
+            costs: Vec::new(),
+            textures: Vec::new(),
+        };
+        let mut odd = file();
+        odd.tile_size = (15.0, 16.0);
+        let mut negative = file();
+        negative.tile_size = (16.0, -16.0);
+
+        for file in [empty, odd, negative] {
+            assert!(matches!(
+                MapTiles::try_from(file),
+                Err(MapFileError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_textures_outside_of_the_tiles_texture() {
+        let mut file = file();
+        file.textures[0][2] = TILE_TEXTURE_COUNT;
+
+        assert!(matches!(
+            MapTiles::try_from(file),
+            Err(MapFileError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_ron_and_json() {
+        let dir = std::env::temp_dir().join(format!("map_file_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let saved: Vec<_> = ["map.ron", "map.json"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                save_map_file(&path, &tiles()).and_then(|()| load_map_file(&path))
+            })
+            .collect();
+        let broken: Vec<_> = ["broken.ron", "broken.json"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, "(width: 3)").unwrap();
+                load_map_file(&path)
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        for tiles in saved {
+            assert_same_tiles(&tiles.unwrap(), &self::tiles());
+        }
+        assert!(matches!(broken[0], Err(MapFileError::Ron(_))));
+        assert!(matches!(broken[1], Err(MapFileError::Json(_))));
+    }
+}
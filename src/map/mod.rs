@@ -1,16 +1,45 @@
+mod connectivity;
+mod editor;
 mod generate_map;
 mod generate_navmesh;
+mod image_map;
+mod map_file;
+pub mod movingai;
+mod progress;
+mod tiled_map;
 
-use bevy::prelude::{App, Component, Plugin, Query, ResMut, State, SystemSet, Transform, Vec3};
-use bevy_ecs_tilemap::prelude::TilemapType;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::{
+    info, App, Commands, Component, DespawnRecursiveExt, Entity, Input, KeyCode, Plugin, Query,
+    Res, ResMut, State, SystemSet, Transform, Vec2, Vec3, With,
+};
+use bevy_ecs_tilemap::{
+    prelude::{TilemapTileSize, TilemapType},
+    tiles::{TilePos, TileStorage},
+};
 use bevy_prototype_debug_lines::{DebugLines, DebugLinesPlugin};
 
 use crate::{
-    map::generate_map::generate_map,
-    map::generate_navmesh::generate_map_namvesh_square_unoptimized, GameState,
+    map::connectivity::{connect_map_regions, poll_map_connectivity},
+    map::editor::{
+        editor_input, paint_tiles, rebuild_edited_navmesh, setup_editor_text, update_editor_text,
+    },
+    map::generate_map::{poll_map_generation, start_map_generation},
+    map::generate_navmesh::{poll_navmesh_generation, start_navmesh_generation},
+    map::map_file::save_map,
+    map::progress::{cleanup_progress_screen, setup_progress_screen, update_progress_screen},
+    navigation::TempNavmesh,
+    GameState,
 };
 
-pub use crate::map::generate_navmesh::TempNavmesh;
+pub use crate::map::connectivity::{ensure_connectivity, ConnectivitySettings};
+pub(crate) use crate::map::editor::EditorText;
+pub use crate::map::editor::{EditorTool, MapEdited, MapEditor};
+pub use crate::map::image_map::{MapPalette, PaletteEntry};
+pub use crate::map::map_file::{load_map_file, save_map_file, MapFile, MapFileError};
+pub use crate::map::progress::{GenerationProgress, ProgressReporter};
+pub use crate::map::tiled_map::TiledSettings;
 
 pub struct MapPlugin;
 
@@ -22,52 +51,230 @@ impl Plugin for MapPlugin {
             width: MAP_SIZE.0,
             height: MAP_SIZE.1,
         })
-        .add_system_set(SystemSet::on_enter(GameState::MapGeneration).with_system(generate_map))
+        .init_resource::<MapSource>()
+        .init_resource::<MapSeed>()
+        .init_resource::<MapPalette>()
+        .init_resource::<TiledSettings>()
+        .init_resource::<ConnectivitySettings>()
+        .init_resource::<MapEditor>()
+        .add_event::<MapEdited>()
+        .init_resource::<GenerationProgress>()
+        .add_system_set(
+            SystemSet::on_enter(GameState::MapGeneration)
+                .with_system(setup_progress_screen)
+                .with_system(start_map_generation.after(setup_progress_screen)),
+        )
         .add_system_set(
             SystemSet::on_update(GameState::MapGeneration)
-                .with_system(move_to_navmesh_state)
-                .after(generate_map),
+                .with_system(poll_map_generation)
+                .with_system(update_progress_screen),
+        )
+        .add_system_set(
+            SystemSet::on_enter(GameState::MapPostProcessing).with_system(connect_map_regions),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::MapPostProcessing)
+                .with_system(poll_map_connectivity)
+                .with_system(update_progress_screen),
         )
         .add_system_set(
-            SystemSet::on_enter(GameState::NavMeshGeneration)
-                .with_system(generate_map_namvesh_square_unoptimized),
+            SystemSet::on_enter(GameState::NavMeshGeneration).with_system(start_navmesh_generation),
         )
         .add_system_set(
             SystemSet::on_update(GameState::NavMeshGeneration)
-                .with_system(move_to_gameplay_state)
-                .after(generate_map_namvesh_square_unoptimized),
+                .with_system(poll_navmesh_generation)
+                .with_system(update_progress_screen),
+        )
+        .add_system_set(
+            SystemSet::on_exit(GameState::NavMeshGeneration).with_system(cleanup_progress_screen),
+        )
+        .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(setup_editor_text))
+        .add_system_set(
+            SystemSet::on_update(GameState::Playing)
+                .with_system(regenerate_map)
+                .with_system(save_map)
+                .with_system(editor_input)
+                .with_system(paint_tiles.after(editor_input))
+                .with_system(rebuild_edited_navmesh.after(paint_tiles))
+                .with_system(update_editor_text.after(editor_input)),
         )
+        .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_map))
         // .add_system_set(SystemSet::on_update(GameState::Playing).with_system(draw_navmesh))
         // .add_system(draw_navmesh)
         .add_plugin(DebugLinesPlugin::default());
     }
 }
 
-fn move_to_navmesh_state(mut state: ResMut<State<GameState>>) {
-    state
-        .set(GameState::NavMeshGeneration)
-        .expect("Unable to transition from map gen state to navmesh gen state");
+/// Generates a new map with a random seed when pressing R.
+fn regenerate_map(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut map_seed: ResMut<MapSeed>,
+    mut map_source: ResMut<MapSource>,
+    mut map_dimensions: ResMut<MapDimensions>,
+    mut state: ResMut<State<GameState>>,
+) {
+    if keyboard_input.just_pressed(KeyCode::R) {
+        map_seed.0 = fastrand::u64(..);
+        *map_source = MapSource::Generated;
+        // A loaded map replaced the dimensions with its own
+        *map_dimensions = MapDimensions::default();
+        info!("regenerating map with seed {}", map_seed.0);
+        state
+            .set(GameState::MapGeneration)
+            .expect("Unable to transition from playing state to map gen state");
+    }
 }
 
-fn move_to_gameplay_state(mut state: ResMut<State<GameState>>) {
-    state
-        .set(GameState::Playing)
-        .expect("Unable to transition from navmesh gen state to playing state");
+/// Despawns the tilemaps, their tiles and the editor UI when leaving `GameState::Playing`.
+fn despawn_map(
+    mut commands: Commands,
+    tilemap_q: Query<(Entity, &TileStorage)>,
+    editor_text_q: Query<Entity, With<EditorText>>,
+) {
+    for (tilemap, tile_storage) in tilemap_q.iter() {
+        for tile_entity in tile_storage.iter().flatten() {
+            commands.entity(*tile_entity).despawn_recursive();
+        }
+        commands.entity(tilemap).despawn_recursive();
+    }
+    for entity in editor_text_q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
 }
 
-#[derive(Component)]
-pub struct TileCost(i8);
+// #[derive(Resource)]
+pub struct MapDimensions {
+    pub width: u32,
+    pub height: u32,
+}
 
-impl Default for TileCost {
+impl Default for MapDimensions {
     fn default() -> Self {
-        TileCost(1)
+        MapDimensions {
+            width: MAP_SIZE.0,
+            height: MAP_SIZE.1,
+        }
     }
 }
 
-// #[derive(Resource)]
-pub struct MapDimensions {
+/// Seed used to generate random maps.
+pub struct MapSeed(pub u64);
+
+impl Default for MapSeed {
+    fn default() -> Self {
+        MapSeed(1)
+    }
+}
+
+/// Where the map comes from when entering `GameState::MapGeneration`.
+#[derive(Clone)]
+pub enum MapSource {
+    Generated,
+    /// A PNG with one pixel per tile, converted to tile costs with the [`MapPalette`]
+    Image(PathBuf),
+    /// A `.tmx` or `.tmj` map from the Tiled editor, see [`TiledSettings`]
+    Tiled(PathBuf),
+    /// A map saved with [`save_map_file`], in RON or JSON
+    File(PathBuf),
+}
+
+impl Default for MapSource {
+    fn default() -> Self {
+        MapSource::Generated
+    }
+}
+
+impl MapSource {
+    /// Picks a source based on the file extension, in any case.
+    pub fn from_path(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        match extension(&path).as_deref() {
+            Some("png") => Ok(MapSource::Image(path)),
+            Some("tmx") | Some("tmj") => Ok(MapSource::Tiled(path)),
+            Some("ron") | Some("json") => Ok(MapSource::File(path)),
+            _ => Err(format!(
+                "unsupported map {}, expected a .png, .tmx, .tmj, .ron or .json file",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Extension of a file in lowercase
+pub(crate) fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+}
+
+/// Size of a tile in pixels, unless the map says otherwise.
+pub const TILE_SIZE: Vec2 = Vec2::new(16.0, 16.0);
+
+/// The cost and texture of every tile of a map, row by row starting from the bottom.
+pub struct MapTiles {
     pub width: u32,
     pub height: u32,
+    pub tile_size: Vec2,
+    pub costs: Vec<i8>,
+    pub textures: Vec<u32>,
+}
+
+impl MapTiles {
+    /// Uses the default tile size, with textures matching the costs.
+    pub fn from_costs(width: u32, height: u32, costs: Vec<i8>) -> Self {
+        MapTiles {
+            width,
+            height,
+            tile_size: TILE_SIZE,
+            textures: costs.iter().map(|cost| texture_for_cost(*cost)).collect(),
+            costs,
+        }
+    }
+
+    /// Reads the tiles of a spawned tilemap, `get_tile` returns the position, cost and texture
+    /// of a tile entity.
+    pub(crate) fn from_tilemap(
+        tile_storage: &TileStorage,
+        tile_size: &TilemapTileSize,
+        get_tile: impl Fn(Entity) -> Option<(TilePos, i8, u32)>,
+    ) -> Self {
+        let (width, height) = (tile_storage.size.x, tile_storage.size.y);
+        let mut tiles = MapTiles {
+            width,
+            height,
+            tile_size: Vec2::new(tile_size.x, tile_size.y),
+            costs: vec![0; (width * height) as usize],
+            textures: vec![0; (width * height) as usize],
+        };
+        for tile_entity in tile_storage.iter().flatten() {
+            if let Some((tile_pos, cost, texture)) = get_tile(*tile_entity) {
+                let idx = (tile_pos.y * width + tile_pos.x) as usize;
+                tiles.costs[idx] = cost;
+                tiles.textures[idx] = texture;
+            }
+        }
+        tiles
+    }
+
+    pub fn cost(&self, x: u32, y: u32) -> i8 {
+        self.costs[(y * self.width + x) as usize]
+    }
+
+    pub fn texture(&self, x: u32, y: u32) -> u32 {
+        self.textures[(y * self.width + x) as usize]
+    }
+}
+
+/// Textures in the tiles texture, a single row of tiles
+pub(crate) const TILE_TEXTURE_COUNT: u32 = 6;
+
+/// Index in the tiles texture for a tile of this cost.
+pub(crate) fn texture_for_cost(cost: i8) -> u32 {
+    if cost < 1 {
+        1
+    } else {
+        0
+    }
 }
 
 #[derive(Component)]
@@ -132,3 +339,38 @@ fn draw_navmesh(
         // }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_source_from_extension_in_any_case() {
+        assert!(matches!(
+            MapSource::from_path("maps/arena.PNG"),
+            Ok(MapSource::Image(_))
+        ));
+        assert!(matches!(
+            MapSource::from_path("arena.Tmx"),
+            Ok(MapSource::Tiled(_))
+        ));
+        assert!(matches!(
+            MapSource::from_path("arena.tmj"),
+            Ok(MapSource::Tiled(_))
+        ));
+        assert!(matches!(
+            MapSource::from_path("map_1.RON"),
+            Ok(MapSource::File(_))
+        ));
+        assert!(matches!(
+            MapSource::from_path("map_1.json"),
+            Ok(MapSource::File(_))
+        ));
+    }
+
+    #[test]
+    fn map_source_rejects_other_files() {
+        assert!(MapSource::from_path("arena.bmp").is_err());
+        assert!(MapSource::from_path("arena").is_err());
+    }
+}
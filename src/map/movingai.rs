@@ -0,0 +1,331 @@
+//! Loaders for the MovingAI grid benchmark formats.
+//! See https://movingai.com/benchmarks/formats.html
+
+use std::{fmt, fs, io, path::Path};
+
+use bevy::prelude::Vec2;
+use polyanya::Mesh as PAMesh;
+
+use crate::navigation::build_square_navmesh;
+
+/// Largest ratio between the octile and euclidean distance of a move, reached at a slope of
+/// `sqrt(2) - 1`. An any-angle path can be at most this much shorter than the optimal octile path.
+pub const OCTILE_TO_ANY_ANGLE_RATIO: f32 = 1.082_392_2;
+
+/// Size of a cell in navmesh units. The square navmesh generator needs tile corners on integer
+/// coordinates, so cells can't be a single unit wide.
+pub const CELL_SIZE: f32 = 2.0;
+
+#[derive(Debug)]
+pub enum MovingAiError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for MovingAiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovingAiError::Io(err) => write!(f, "io error: {}", err),
+            MovingAiError::Parse { line, message } => {
+                write!(f, "parse error on line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MovingAiError {}
+
+impl From<io::Error> for MovingAiError {
+    fn from(err: io::Error) -> Self {
+        MovingAiError::Io(err)
+    }
+}
+
+fn parse_error(line: usize, message: impl Into<String>) -> MovingAiError {
+    MovingAiError::Parse {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+/// A `.map` file. Row 0 is the first row of the file.
+pub struct MovingAiMap {
+    pub width: u32,
+    pub height: u32,
+    pub walkable: Vec<bool>,
+}
+
+impl MovingAiMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MovingAiError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, MovingAiError> {
+        let mut lines = contents.lines().enumerate();
+        let mut width = None;
+        let mut height = None;
+
+        for (line_nb, line) in lines.by_ref() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("type"), _) => {}
+                (Some("height"), Some(value)) => {
+                    height = Some(
+                        value
+                            .parse()
+                            .map_err(|_| parse_error(line_nb, "bad height"))?,
+                    )
+                }
+                (Some("width"), Some(value)) => {
+                    width = Some(
+                        value
+                            .parse()
+                            .map_err(|_| parse_error(line_nb, "bad width"))?,
+                    )
+                }
+                (Some("map"), _) => break,
+                _ => {
+                    return Err(parse_error(
+                        line_nb,
+                        format!("unexpected header `{}`", line),
+                    ))
+                }
+            }
+        }
+
+        let (width, height): (u32, u32) = match (width, height) {
+            (Some(width), Some(height)) => (width, height),
+            _ => return Err(parse_error(0, "missing width or height")),
+        };
+
+        let mut walkable = Vec::with_capacity((width * height) as usize);
+        for (line_nb, line) in lines.take(height as usize) {
+            let row = line.trim_end();
+            if row.len() != width as usize {
+                return Err(parse_error(line_nb, "row length doesn't match width"));
+            }
+            // `.`, `G` and `S` are passable, `@`, `O`, `T` and `W` are not
+            walkable.extend(row.chars().map(|c| matches!(c, '.' | 'G' | 'S')));
+        }
+        if walkable.len() != (width * height) as usize {
+            return Err(parse_error(0, "not enough rows for height"));
+        }
+
+        Ok(MovingAiMap {
+            width,
+            height,
+            walkable,
+        })
+    }
+
+    pub fn is_walkable(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.walkable[(y * self.width + x) as usize]
+    }
+
+    /// Builds a navmesh where each cell is [`CELL_SIZE`] units wide. Cell `(x, y)` covers
+    /// `(x, y) * CELL_SIZE..(x + 1, y + 1) * CELL_SIZE`.
+    pub fn build_navmesh(&self) -> PAMesh {
+        let tiles = (0..self.height).flat_map(|y| {
+            (0..self.width).map(move |x| {
+                (
+                    (Vec2::new(x as f32, y as f32) + 0.5) * CELL_SIZE,
+                    self.is_walkable(x, y),
+                )
+            })
+        });
+        build_square_navmesh(tiles, Vec2::splat(CELL_SIZE), self.walkable.len())
+    }
+}
+
+pub struct ScenarioQuery {
+    pub bucket: u32,
+    pub map: String,
+    pub start: Vec2,
+    pub goal: Vec2,
+    pub optimal_length: f32,
+}
+
+/// A `.scen` file listing start/goal pairs and their optimal octile lengths.
+pub struct Scenario {
+    pub queries: Vec<ScenarioQuery>,
+}
+
+impl Scenario {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MovingAiError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, MovingAiError> {
+        let mut queries = Vec::new();
+        for (line_nb, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with("version") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 9 {
+                return Err(parse_error(line_nb, "expected 9 tab separated fields"));
+            }
+            let number = |idx: usize| -> Result<f32, MovingAiError> {
+                fields[idx]
+                    .trim()
+                    .parse()
+                    .map_err(|_| parse_error(line_nb, format!("bad number `{}`", fields[idx])))
+            };
+            // Start and goal are cells, query from their centers. Coordinates and lengths are
+            // in cells, see `CELL_SIZE` to convert them to navmesh units.
+            queries.push(ScenarioQuery {
+                bucket: number(0)? as u32,
+                map: fields[1].to_string(),
+                start: Vec2::new(number(4)? + 0.5, number(5)? + 0.5),
+                goal: Vec2::new(number(6)? + 0.5, number(7)? + 0.5),
+                optimal_length: number(8)?,
+            });
+        }
+        Ok(Scenario { queries })
+    }
+}
+
+/// Checks a path length against the optimal octile length of a scenario query.
+///
+/// Any-angle paths are never longer than octile paths, and can't be shorter than the octile
+/// length divided by [`OCTILE_TO_ANY_ANGLE_RATIO`].
+pub fn is_length_consistent(length: Option<f32>, optimal_length: f32, epsilon: f32) -> bool {
+    match length {
+        Some(length) => {
+            length <= optimal_length + epsilon
+                && length >= optimal_length / OCTILE_TO_ANY_ANGLE_RATIO - epsilon
+        }
+        // Start and goal are the same cell
+        None => optimal_length <= epsilon,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "type octile\nheight 3\nwidth 4\nmap\n....\n.@@.\nT..G\n";
+
+    const SCENARIO: &str = "version 1\n\
+        0\tmaps/test.map\t4\t3\t0\t0\t3\t2\t3.82842712\n\
+        1\tmaps/test.map\t4\t3\t3\t0\t3\t0\t0\n";
+
+    /// Octile distance, the optimal length in `.scen` files
+    fn octile(dx: f32, dy: f32) -> f32 {
+        let (long, short) = (dx.abs().max(dy.abs()), dx.abs().min(dy.abs()));
+        long - short + short * std::f32::consts::SQRT_2
+    }
+
+    #[test]
+    fn parses_map() {
+        let map = MovingAiMap::parse(MAP).unwrap();
+        assert_eq!((map.width, map.height), (4, 3));
+        assert!(map.is_walkable(0, 0));
+        assert!(!map.is_walkable(1, 1));
+        assert!(!map.is_walkable(2, 1));
+        assert!(!map.is_walkable(0, 2));
+        assert!(map.is_walkable(3, 2));
+        // Outside of the map
+        assert!(!map.is_walkable(4, 0));
+        assert!(!map.is_walkable(0, 3));
+    }
+
+    #[test]
+    fn rejects_bad_maps() {
+        let short_row = "type octile\nheight 2\nwidth 3\nmap\n...\n..\n";
+        assert!(matches!(
+            MovingAiMap::parse(short_row),
+            Err(MovingAiError::Parse { line: 6, .. })
+        ));
+        let missing_row = "type octile\nheight 2\nwidth 3\nmap\n...\n";
+        assert!(MovingAiMap::parse(missing_row).is_err());
+        let no_width = "type octile\nheight 2\nmap\n...\n...\n";
+        assert!(MovingAiMap::parse(no_width).is_err());
+        let bad_header = "type octile\nheight 2\nwidth 3\ndepth 1\nmap\n...\n...\n";
+        assert!(matches!(
+            MovingAiMap::parse(bad_header),
+            Err(MovingAiError::Parse { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn parses_scenario() {
+        let scenario = Scenario::parse(SCENARIO).unwrap();
+        assert_eq!(scenario.queries.len(), 2);
+        let query = &scenario.queries[0];
+        assert_eq!(query.bucket, 0);
+        assert_eq!(query.map, "maps/test.map");
+        assert_eq!(query.start, Vec2::new(0.5, 0.5));
+        assert_eq!(query.goal, Vec2::new(3.5, 2.5));
+        assert!((query.optimal_length - octile(3.0, 2.0)).abs() < 1e-6);
+        assert_eq!(scenario.queries[1].optimal_length, 0.0);
+    }
+
+    #[test]
+    fn rejects_bad_scenarios() {
+        let missing_field = "version 1\n0\tmaps/test.map\t4\t3\t0\t0\t3\t2\n";
+        assert!(matches!(
+            Scenario::parse(missing_field),
+            Err(MovingAiError::Parse { line: 2, .. })
+        ));
+        let bad_number = "version 1\n0\tmaps/test.map\t4\t3\tx\t0\t3\t2\t1\n";
+        assert!(Scenario::parse(bad_number).is_err());
+    }
+
+    #[test]
+    fn ratio_is_reached_at_the_worst_slope() {
+        let dy = std::f32::consts::SQRT_2 - 1.0;
+        let ratio = octile(1.0, dy) / Vec2::new(1.0, dy).length();
+        assert!((ratio - OCTILE_TO_ANY_ANGLE_RATIO).abs() < 1e-5);
+        // Any other slope gains less
+        for dy in [0.0, 0.25, 0.5, 0.75, 1.0] {
+            assert!(octile(1.0, dy) / Vec2::new(1.0, dy).length() <= OCTILE_TO_ANY_ANGLE_RATIO);
+        }
+    }
+
+    #[test]
+    fn any_angle_length_is_consistent_with_octile() {
+        let epsilon = 0.01;
+        // Straight moves are the same in both metrics
+        assert!(is_length_consistent(Some(5.0), octile(5.0, 0.0), epsilon));
+        // The any-angle path of a knight move is shorter than its octile length
+        let optimal = octile(2.0, 1.0);
+        assert!(is_length_consistent(
+            Some(Vec2::new(2.0, 1.0).length()),
+            optimal,
+            epsilon
+        ));
+        // Longer than the octile length, the search missed the optimal path
+        assert!(!is_length_consistent(
+            Some(optimal + 2.0 * epsilon),
+            optimal,
+            epsilon
+        ));
+    }
+
+    #[test]
+    fn shortest_possible_any_angle_length_is_the_boundary() {
+        let epsilon = 0.01;
+        let dy = std::f32::consts::SQRT_2 - 1.0;
+        let optimal = octile(10.0, 10.0 * dy);
+        let shortest = Vec2::new(10.0, 10.0 * dy).length();
+        assert!(is_length_consistent(Some(shortest), optimal, epsilon));
+        assert!(is_length_consistent(
+            Some(shortest - epsilon / 2.0),
+            optimal,
+            epsilon
+        ));
+        // Shorter than any path could be, the path goes through a wall
+        assert!(!is_length_consistent(
+            Some(shortest - 2.0 * epsilon),
+            optimal,
+            epsilon
+        ));
+    }
+
+    #[test]
+    fn missing_path_is_only_consistent_for_the_same_cell() {
+        assert!(is_length_consistent(None, 0.0, 0.01));
+        assert!(!is_length_consistent(None, 1.0, 0.01));
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use bevy::{prelude::*, utils::Instant};
+
+use crate::{loading::FontAssets, GameState};
+
+/// Progress of the current generation phase, shared with the task doing the work.
+#[derive(Clone, Default)]
+pub struct ProgressReporter(Arc<AtomicU32>);
+
+impl ProgressReporter {
+    /// `fraction` goes from 0.0 to 1.0
+    pub fn set(&self, fraction: f32) {
+        self.0
+            .store(fraction.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Progress of map and navmesh generation, displayed until the game starts.
+pub struct GenerationProgress {
+    pub started: Instant,
+    pub phase: ProgressReporter,
+}
+
+impl Default for GenerationProgress {
+    fn default() -> Self {
+        GenerationProgress {
+            started: Instant::now(),
+            phase: ProgressReporter::default(),
+        }
+    }
+}
+
+impl GenerationProgress {
+    /// Resets the progress for a new phase, returning the reporter for it.
+    pub fn start_phase(&mut self) -> ProgressReporter {
+        self.phase = ProgressReporter::default();
+        self.phase.clone()
+    }
+}
+
+#[derive(Component)]
+pub(crate) struct ProgressText;
+
+pub(crate) fn setup_progress_screen(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    mut progress: ResMut<GenerationProgress>,
+) {
+    *progress = GenerationProgress::default();
+
+    commands
+        .spawn_bundle(TextBundle {
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    font: font_assets.fira_sans.clone(),
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                },
+            ),
+            style: Style {
+                margin: UiRect::all(Val::Auto),
+                ..default()
+            },
+            ..default()
+        })
+        .insert(ProgressText);
+}
+
+pub(crate) fn update_progress_screen(
+    progress: Res<GenerationProgress>,
+    state: Res<State<GameState>>,
+    mut text_q: Query<&mut Text, With<ProgressText>>,
+) {
+    let phase = match state.current() {
+        GameState::MapGeneration => "Generating map",
+        GameState::MapPostProcessing => "Connecting map regions",
+        GameState::NavMeshGeneration => "Building navmesh",
+        _ => return,
+    };
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = format!(
+            "{}: {:.0}%\n{:.1}s",
+            phase,
+            progress.phase.get() * 100.0,
+            progress.started.elapsed().as_secs_f32()
+        );
+    }
+}
+
+pub(crate) fn cleanup_progress_screen(
+    mut commands: Commands,
+    text_q: Query<Entity, With<ProgressText>>,
+) {
+    for entity in text_q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
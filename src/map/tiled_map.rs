@@ -0,0 +1,833 @@
+//! Loader for maps made with the Tiled editor, in either the XML (`.tmx`) or the JSON (`.tmj`)
+//! format. See https://doc.mapeditor.org/en/stable/reference/tmx-map-format/
+//!
+//! Only finite maps are supported, with tile layers stored as CSV (the default in Tiled) or as a
+//! JSON array. Tiles are rendered with our own texture, so only their cost and size are kept.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::{math::Vec2, utils::HashMap};
+use serde::Deserialize;
+
+use super::{extension, map_file::is_valid_tile_size, MapTiles};
+
+/// Tiled stores flip and rotation flags in the highest bits of a tile gid
+const GID_FLAGS: u32 = 0xF000_0000;
+
+/// Cost of tiles covered by a blocking object.
+const BLOCKED_COST: i8 = -1;
+
+/// How tile costs are read from a Tiled map.
+#[derive(Clone)]
+pub struct TiledSettings {
+    /// Name of the custom tile property holding the cost of a tile
+    pub cost_property: String,
+    /// Cost of tiles that don't have the cost property. In the layers above another tile, such
+    /// tiles are decorations and keep the cost of the tile below.
+    pub default_cost: i8,
+    /// Cost of cells without a tile in any layer
+    pub empty_cost: i8,
+    /// If rectangles in object layers block the tiles they overlap
+    pub objects_block: bool,
+}
+
+impl Default for TiledSettings {
+    fn default() -> Self {
+        TiledSettings {
+            cost_property: "cost".to_string(),
+            default_cost: 1,
+            empty_cost: BLOCKED_COST,
+            objects_block: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TiledError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Xml(roxmltree::Error),
+    Unsupported(String),
+    Invalid(String),
+}
+
+impl fmt::Display for TiledError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TiledError::Io(err) => write!(f, "io error: {}", err),
+            TiledError::Json(err) => write!(f, "json error: {}", err),
+            TiledError::Xml(err) => write!(f, "xml error: {}", err),
+            TiledError::Unsupported(message) => write!(f, "unsupported: {}", message),
+            TiledError::Invalid(message) => write!(f, "invalid map: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TiledError {}
+
+impl From<io::Error> for TiledError {
+    fn from(err: io::Error) -> Self {
+        TiledError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for TiledError {
+    fn from(err: serde_json::Error) -> Self {
+        TiledError::Json(err)
+    }
+}
+
+impl From<roxmltree::Error> for TiledError {
+    fn from(err: roxmltree::Error) -> Self {
+        TiledError::Xml(err)
+    }
+}
+
+/// A rectangle in pixels, with y going down from the top of the map
+struct ObjectRect {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+/// The parts of a Tiled map that matter for walkability, independent of the file format
+#[derive(Default)]
+struct TiledMap {
+    width: u32,
+    height: u32,
+    tile_width: f32,
+    tile_height: f32,
+    /// Tile costs by gid, for tiles with the cost property
+    costs: HashMap<u32, i8>,
+    /// gids of each visible tile layer, from the bottom layer to the top one
+    layers: Vec<Vec<u32>>,
+    objects: Vec<ObjectRect>,
+}
+
+impl TiledMap {
+    fn into_tiles(self, settings: &TiledSettings) -> Result<MapTiles, TiledError> {
+        if !is_valid_tile_size(self.tile_width) || !is_valid_tile_size(self.tile_height) {
+            return Err(TiledError::Unsupported(format!(
+                "tile size of {}x{}, tiles must have an even number of pixels on each side",
+                self.tile_width, self.tile_height
+            )));
+        }
+        let count = (self.width * self.height) as usize;
+        let mut costs: Vec<Option<i8>> = vec![None; count];
+
+        for layer in self.layers.iter() {
+            if layer.len() != count {
+                return Err(TiledError::Invalid(format!(
+                    "layer has {} tiles, expected {}",
+                    layer.len(),
+                    count
+                )));
+            }
+            for (idx, gid) in layer.iter().enumerate() {
+                let gid = gid & !GID_FLAGS;
+                if gid == 0 {
+                    continue;
+                }
+                // tilemap y goes up, Tiled rows go down
+                let (x, row) = (idx as u32 % self.width, idx as u32 / self.width);
+                let y = self.height - 1 - row;
+                let cost = &mut costs[(y * self.width + x) as usize];
+                match self.costs.get(&gid) {
+                    Some(explicit) => *cost = Some(*explicit),
+                    None if cost.is_none() => *cost = Some(settings.default_cost),
+                    None => {}
+                }
+            }
+        }
+        let mut costs: Vec<i8> = costs
+            .into_iter()
+            .map(|cost| cost.unwrap_or(settings.empty_cost))
+            .collect();
+
+        if settings.objects_block {
+            for object in self.objects.iter() {
+                let first_col = (object.x / self.tile_width).floor().max(0.0) as u32;
+                let last_col = ((object.x + object.width) / self.tile_width).ceil() as u32;
+                let first_row = (object.y / self.tile_height).floor().max(0.0) as u32;
+                let last_row = ((object.y + object.height) / self.tile_height).ceil() as u32;
+                for row in first_row..last_row.min(self.height) {
+                    for x in first_col..last_col.min(self.width) {
+                        let y = self.height - 1 - row;
+                        costs[(y * self.width + x) as usize] = BLOCKED_COST;
+                    }
+                }
+            }
+        }
+
+        Ok(MapTiles {
+            tile_size: Vec2::new(self.tile_width, self.tile_height),
+            ..MapTiles::from_costs(self.width, self.height, costs)
+        })
+    }
+}
+
+fn parse_cost(value: &str) -> Option<i8> {
+    value
+        .trim()
+        .parse::<i8>()
+        .ok()
+        .or_else(|| value.trim().parse::<f32>().ok().map(|cost| cost as i8))
+}
+
+/// Loads a `.tmx` or `.tmj` map and converts it to tile costs.
+pub fn load_tiled_map(
+    path: impl AsRef<Path>,
+    settings: &TiledSettings,
+) -> Result<MapTiles, TiledError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let map = match extension(path).as_deref() {
+        Some("tmx") => parse_tmx(&contents, path, settings)?,
+        Some("tmj") | Some("json") => parse_tmj(&contents, path, settings)?,
+        _ => {
+            return Err(TiledError::Unsupported(format!(
+                "unknown extension for {}",
+                path.display()
+            )))
+        }
+    };
+    map.into_tiles(settings)
+}
+
+/// Path of an external tileset, relative to the map referencing it
+fn tileset_path(map_path: &Path, source: &str) -> PathBuf {
+    map_path
+        .parent()
+        .map(|dir| dir.join(source))
+        .unwrap_or_else(|| PathBuf::from(source))
+}
+
+/// Reads the cost of each tile of an external tileset, by local tile id
+fn load_external_tileset(
+    path: &Path,
+    settings: &TiledSettings,
+) -> Result<HashMap<u32, i8>, TiledError> {
+    let contents = fs::read_to_string(path)?;
+    match extension(path).as_deref() {
+        Some("tsx") => {
+            let document = roxmltree::Document::parse(&contents)?;
+            Ok(tmx_tileset_costs(document.root_element(), settings))
+        }
+        Some("tsj") | Some("json") => {
+            let tileset: JsonTileset = serde_json::from_str(&contents)?;
+            Ok(tmj_tileset_costs(&tileset, settings))
+        }
+        _ => Err(TiledError::Unsupported(format!(
+            "unknown extension for tileset {}",
+            path.display()
+        ))),
+    }
+}
+
+fn add_tileset_costs(map: &mut TiledMap, first_gid: u32, costs: HashMap<u32, i8>) {
+    map.costs
+        .extend(costs.into_iter().map(|(id, cost)| (first_gid + id, cost)));
+}
+
+fn tmx_attribute<T: std::str::FromStr>(node: roxmltree::Node, name: &str) -> Result<T, TiledError> {
+    node.attribute(name)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| {
+            TiledError::Invalid(format!(
+                "missing or invalid attribute `{}` on <{}>",
+                name,
+                node.tag_name().name()
+            ))
+        })
+}
+
+fn tmx_tileset_costs(tileset: roxmltree::Node, settings: &TiledSettings) -> HashMap<u32, i8> {
+    tileset
+        .children()
+        .filter(|node| node.has_tag_name("tile"))
+        .filter_map(|tile| {
+            let id = tile.attribute("id")?.parse().ok()?;
+            let cost = tile
+                .descendants()
+                .filter(|node| node.has_tag_name("property"))
+                .find(|property| {
+                    property.attribute("name") == Some(settings.cost_property.as_str())
+                })
+                .and_then(|property| property.attribute("value"))
+                .and_then(parse_cost)?;
+            Some((id, cost))
+        })
+        .collect()
+}
+
+fn parse_tmx(
+    contents: &str,
+    path: &Path,
+    settings: &TiledSettings,
+) -> Result<TiledMap, TiledError> {
+    let document = roxmltree::Document::parse(contents)?;
+    let root = document.root_element();
+    if root.attribute("infinite") == Some("1") {
+        return Err(TiledError::Unsupported("infinite maps".to_string()));
+    }
+
+    let mut map = TiledMap {
+        width: tmx_attribute(root, "width")?,
+        height: tmx_attribute(root, "height")?,
+        tile_width: tmx_attribute(root, "tilewidth")?,
+        tile_height: tmx_attribute(root, "tileheight")?,
+        ..Default::default()
+    };
+
+    for tileset in root.children().filter(|node| node.has_tag_name("tileset")) {
+        let first_gid = tmx_attribute(tileset, "firstgid")?;
+        let costs = match tileset.attribute("source") {
+            Some(source) => load_external_tileset(&tileset_path(path, source), settings)?,
+            None => tmx_tileset_costs(tileset, settings),
+        };
+        add_tileset_costs(&mut map, first_gid, costs);
+    }
+
+    // Groups can nest layers, `descendants` visits them in drawing order
+    for node in root.descendants() {
+        // Tiles can have their own collision objects. Hidden layers and groups are ignored,
+        // `ancestors` includes the node itself
+        if node.ancestors().any(|ancestor| {
+            ancestor.has_tag_name("tileset") || ancestor.attribute("visible") == Some("0")
+        }) {
+            continue;
+        }
+        if node.has_tag_name("layer") {
+            let data = node
+                .children()
+                .find(|child| child.has_tag_name("data"))
+                .ok_or_else(|| TiledError::Invalid("layer without data".to_string()))?;
+            let gids = match data.attribute("encoding") {
+                Some("csv") => data
+                    .text()
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|gid| {
+                        gid.trim()
+                            .parse()
+                            .map_err(|_| TiledError::Invalid(format!("bad tile gid `{}`", gid)))
+                    })
+                    .collect::<Result<Vec<u32>, _>>()?,
+                None => data
+                    .children()
+                    .filter(|child| child.has_tag_name("tile"))
+                    .map(|tile| tile.attribute("gid").and_then(|gid| gid.parse().ok()))
+                    .map(|gid| gid.unwrap_or(0))
+                    .collect(),
+                Some(encoding) => {
+                    return Err(TiledError::Unsupported(format!(
+                        "{} tile layer encoding, save the map with CSV encoding",
+                        encoding
+                    )))
+                }
+            };
+            map.layers.push(gids);
+        } else if node.has_tag_name("object") {
+            // Only rectangles, other shapes have a child element describing them
+            if node.children().any(|child| {
+                ["ellipse", "point", "polygon", "polyline", "text"]
+                    .iter()
+                    .any(|shape| child.has_tag_name(*shape))
+            }) {
+                continue;
+            }
+            let (width, height) = (
+                tmx_attribute(node, "width").unwrap_or(0.0),
+                tmx_attribute(node, "height").unwrap_or(0.0),
+            );
+            // Tile objects have a gid and no area to block
+            if node.attribute("gid").is_some() || width <= 0.0 || height <= 0.0 {
+                continue;
+            }
+            map.objects.push(ObjectRect {
+                x: tmx_attribute(node, "x")?,
+                y: tmx_attribute(node, "y")?,
+                width,
+                height,
+            });
+        }
+    }
+
+    Ok(map)
+}
+
+#[derive(Deserialize)]
+struct JsonProperty {
+    name: String,
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct JsonTile {
+    id: u32,
+    #[serde(default)]
+    properties: Vec<JsonProperty>,
+}
+
+#[derive(Deserialize)]
+struct JsonTileset {
+    #[serde(default)]
+    firstgid: u32,
+    source: Option<String>,
+    #[serde(default)]
+    tiles: Vec<JsonTile>,
+}
+
+#[derive(Deserialize)]
+struct JsonObject {
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    gid: Option<u32>,
+    #[serde(default)]
+    ellipse: bool,
+    #[serde(default)]
+    point: bool,
+    polygon: Option<serde_json::Value>,
+    polyline: Option<serde_json::Value>,
+}
+
+impl JsonObject {
+    fn is_rectangle(&self) -> bool {
+        self.gid.is_none()
+            && !self.ellipse
+            && !self.point
+            && self.polygon.is_none()
+            && self.polyline.is_none()
+            && self.width > 0.0
+            && self.height > 0.0
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonLayer {
+    Tilelayer {
+        data: Option<serde_json::Value>,
+        encoding: Option<String>,
+        #[serde(default = "visible_by_default")]
+        visible: bool,
+    },
+    Objectgroup {
+        objects: Vec<JsonObject>,
+        #[serde(default = "visible_by_default")]
+        visible: bool,
+    },
+    Group {
+        layers: Vec<JsonLayer>,
+        #[serde(default = "visible_by_default")]
+        visible: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+fn visible_by_default() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct JsonMap {
+    width: u32,
+    height: u32,
+    tilewidth: f32,
+    tileheight: f32,
+    #[serde(default)]
+    infinite: bool,
+    #[serde(default)]
+    tilesets: Vec<JsonTileset>,
+    layers: Vec<JsonLayer>,
+}
+
+fn tmj_tileset_costs(tileset: &JsonTileset, settings: &TiledSettings) -> HashMap<u32, i8> {
+    tileset
+        .tiles
+        .iter()
+        .filter_map(|tile| {
+            let property = tile
+                .properties
+                .iter()
+                .find(|property| property.name == settings.cost_property)?;
+            let cost = match &property.value {
+                serde_json::Value::Number(number) => number.as_f64().map(|cost| cost as i8),
+                serde_json::Value::String(value) => parse_cost(value),
+                _ => None,
+            }?;
+            Some((tile.id, cost))
+        })
+        .collect()
+}
+
+fn add_tmj_layers(map: &mut TiledMap, layers: Vec<JsonLayer>) -> Result<(), TiledError> {
+    for layer in layers {
+        match layer {
+            // Hidden layers and groups are ignored
+            JsonLayer::Tilelayer { visible: false, .. }
+            | JsonLayer::Objectgroup { visible: false, .. }
+            | JsonLayer::Group { visible: false, .. } => {}
+            JsonLayer::Tilelayer { data, encoding, .. } => {
+                if let Some(encoding) = encoding.filter(|encoding| encoding != "csv") {
+                    return Err(TiledError::Unsupported(format!(
+                        "{} tile layer encoding, save the map with CSV encoding",
+                        encoding
+                    )));
+                }
+                let data = data.ok_or_else(|| TiledError::Invalid("layer without data".into()))?;
+                map.layers.push(serde_json::from_value(data)?);
+            }
+            JsonLayer::Objectgroup { objects, .. } => {
+                map.objects
+                    .extend(
+                        objects
+                            .into_iter()
+                            .filter(JsonObject::is_rectangle)
+                            .map(|object| ObjectRect {
+                                x: object.x,
+                                y: object.y,
+                                width: object.width,
+                                height: object.height,
+                            }),
+                    );
+            }
+            JsonLayer::Group { layers, .. } => add_tmj_layers(map, layers)?,
+            JsonLayer::Other => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_tmj(
+    contents: &str,
+    path: &Path,
+    settings: &TiledSettings,
+) -> Result<TiledMap, TiledError> {
+    let json_map: JsonMap = serde_json::from_str(contents)?;
+    if json_map.infinite {
+        return Err(TiledError::Unsupported("infinite maps".to_string()));
+    }
+
+    let mut map = TiledMap {
+        width: json_map.width,
+        height: json_map.height,
+        tile_width: json_map.tilewidth,
+        tile_height: json_map.tileheight,
+        ..Default::default()
+    };
+
+    for tileset in json_map.tilesets.iter() {
+        let costs = match &tileset.source {
+            Some(source) => load_external_tileset(&tileset_path(path, source), settings)?,
+            None => tmj_tileset_costs(tileset, settings),
+        };
+        add_tileset_costs(&mut map, tileset.firstgid, costs);
+    }
+
+    add_tmj_layers(&mut map, json_map.layers)?;
+
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tile 0 is walkable, 1 is a wall, 2 costs 5 and 3 has no cost, with gids starting at 1
+    const TILESET: &str = r#"<tileset firstgid="1" name="terrain" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+  <tile id="0"><properties><property name="cost" type="int" value="1"/></properties></tile>
+  <tile id="1"><properties><property name="cost" type="int" value="-1"/></properties></tile>
+  <tile id="2"><properties><property name="cost" type="int" value="5"/></properties></tile>
+ </tileset>"#;
+
+    /// The ground layer of the 3 by 2 test maps, the bottom right cell is empty
+    const GROUND: &str = r#"<layer id="1" name="ground" width="3" height="2">
+  <data encoding="csv">1,2,3,
+1,1,0</data>
+ </layer>"#;
+
+    /// A 3 by 2 map with square tiles of `tile_size` pixels
+    fn tmx_map(tile_size: u32, body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.9" orientation="orthogonal" width="3" height="2" tilewidth="{size}" tileheight="{size}" infinite="0">
+ {body}
+</map>"#,
+            size = tile_size,
+            body = body
+        )
+    }
+
+    fn load_tmx(contents: &str, settings: &TiledSettings) -> Result<MapTiles, TiledError> {
+        parse_tmx(contents, Path::new("map.tmx"), settings)?.into_tiles(settings)
+    }
+
+    fn load_tmj(contents: &str, settings: &TiledSettings) -> Result<MapTiles, TiledError> {
+        parse_tmj(contents, Path::new("map.tmj"), settings)?.into_tiles(settings)
+    }
+
+    /// Costs of the rows of `tiles`, from the top of the map to the bottom like in Tiled
+    fn rows(tiles: &MapTiles) -> Vec<Vec<i8>> {
+        (0..tiles.height)
+            .rev()
+            .map(|y| (0..tiles.width).map(|x| tiles.cost(x, y)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn reads_tile_costs() {
+        let map = format!("{}\n{}", TILESET, GROUND);
+        let tiles = load_tmx(&tmx_map(16, &map), &TiledSettings::default()).unwrap();
+
+        assert_eq!(rows(&tiles), vec![vec![1, -1, 5], vec![1, 1, BLOCKED_COST]]);
+        assert_eq!(tiles.tile_size, Vec2::new(16.0, 16.0));
+    }
+
+    #[test]
+    fn keeps_the_tile_size_of_the_map() {
+        let map = format!("{}\n{}", TILESET, GROUND);
+        let tiles = load_tmx(&tmx_map(32, &map), &TiledSettings::default()).unwrap();
+
+        assert_eq!(tiles.tile_size, Vec2::new(32.0, 32.0));
+    }
+
+    #[test]
+    fn decorations_keep_the_cost_below() {
+        // Tile 3 has no cost: over a tile it's a decoration, over an empty cell it's the default
+        // cost. Tiles with a cost replace the one below.
+        let map = format!(
+            r#"{}
+{}
+<layer id="2" name="decorations" width="3" height="2">
+  <data encoding="csv">0,4,4,
+2,0,4</data>
+ </layer>"#,
+            TILESET, GROUND
+        );
+        let settings = TiledSettings {
+            default_cost: 7,
+            ..Default::default()
+        };
+        let tiles = load_tmx(&tmx_map(16, &map), &settings).unwrap();
+
+        assert_eq!(rows(&tiles), vec![vec![1, -1, 5], vec![-1, 1, 7]]);
+    }
+
+    #[test]
+    fn ignores_hidden_layers() {
+        let map = format!(
+            r#"{}
+{}
+<layer id="2" name="hidden" width="3" height="2" visible="0">
+  <data encoding="csv">2,2,2,
+2,2,2</data>
+ </layer>
+<group id="3" name="hidden group" visible="0">
+  <layer id="4" name="nested" width="3" height="2">
+   <data encoding="csv">3,3,3,
+3,3,3</data>
+  </layer>
+  <objectgroup id="5" name="blocking">
+   <object id="1" x="0" y="0" width="48" height="32"/>
+  </objectgroup>
+ </group>"#,
+            TILESET, GROUND
+        );
+        let tiles = load_tmx(&tmx_map(16, &map), &TiledSettings::default()).unwrap();
+
+        assert_eq!(rows(&tiles), vec![vec![1, -1, 5], vec![1, 1, BLOCKED_COST]]);
+    }
+
+    #[test]
+    fn masks_the_flip_flags_of_gids() {
+        // Tiles 2 and 3, flipped horizontally, vertically and diagonally
+        let map = format!(
+            r#"{}
+<layer id="1" name="ground" width="3" height="2">
+  <data encoding="csv">2147483650,1073741826,536870914,
+2147483651,3221225475,1</data>
+ </layer>"#,
+            TILESET
+        );
+        let tiles = load_tmx(&tmx_map(16, &map), &TiledSettings::default()).unwrap();
+
+        assert_eq!(rows(&tiles), vec![vec![-1, -1, -1], vec![5, 5, 1]]);
+    }
+
+    #[test]
+    fn rectangles_block_the_tiles_they_overlap() {
+        let map = format!(
+            r#"{}
+<layer id="1" name="ground" width="3" height="2">
+  <data encoding="csv">1,1,1,
+1,1,1</data>
+ </layer>
+<objectgroup id="2" name="objects">
+  <object id="1" x="20" y="20" width="8" height="4"/>
+  <object id="2" x="0" y="0" width="16" height="16"><ellipse/></object>
+  <object id="3" gid="2" x="32" y="16" width="16" height="16"/>
+  <object id="4" x="0" y="0"><point/></object>
+ </objectgroup>"#,
+            TILESET
+        );
+        let tiles = load_tmx(&tmx_map(16, &map), &TiledSettings::default()).unwrap();
+        // Only the rectangle blocks, in the second column of the bottom row
+        assert_eq!(rows(&tiles), vec![vec![1, 1, 1], vec![1, BLOCKED_COST, 1]]);
+
+        let settings = TiledSettings {
+            objects_block: false,
+            ..Default::default()
+        };
+        let tiles = load_tmx(&tmx_map(16, &map), &settings).unwrap();
+        assert_eq!(rows(&tiles), vec![vec![1, 1, 1], vec![1, 1, 1]]);
+    }
+
+    #[test]
+    fn reads_several_tilesets() {
+        let map = format!(
+            r#"{}
+<tileset firstgid="5" name="water" tilewidth="16" tileheight="16" tilecount="2" columns="2">
+  <tile id="1"><properties><property name="cost" value="3"/></properties></tile>
+ </tileset>
+<layer id="1" name="ground" width="3" height="2">
+  <data encoding="csv">1,5,6,
+6,2,3</data>
+ </layer>"#,
+            TILESET
+        );
+        let tiles = load_tmx(&tmx_map(16, &map), &TiledSettings::default()).unwrap();
+
+        // Tile 0 of the second tileset has no cost
+        assert_eq!(rows(&tiles), vec![vec![1, 1, 3], vec![3, -1, 5]]);
+    }
+
+    #[test]
+    fn reads_external_tilesets() {
+        let dir = std::env::temp_dir().join(format!("tiled_map_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("walls.tsx"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.9" name="walls" tilewidth="16" tileheight="16" tilecount="2" columns="2">
+ <tile id="1"><properties><property name="cost" type="int" value="-1"/></properties></tile>
+</tileset>"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("walls.tsj"),
+            r#"{"name": "walls", "tiles": [
+                {"id": 0, "properties": [{"name": "cost", "type": "int", "value": 4}]}
+            ]}"#,
+        )
+        .unwrap();
+        let map = format!(
+            r#"{}
+<tileset firstgid="10" source="walls.tsx"/>
+<tileset firstgid="20" source="walls.tsj"/>
+<layer id="1" name="ground" width="3" height="2">
+  <data encoding="csv">1,11,20,
+10,1,1</data>
+ </layer>"#,
+            TILESET
+        );
+        fs::write(dir.join("map.tmx"), tmx_map(16, &map)).unwrap();
+
+        let tiles = load_tiled_map(dir.join("map.tmx"), &TiledSettings::default());
+        let missing = load_tiled_map(dir.join("missing.tmx"), &TiledSettings::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rows(&tiles.unwrap()), vec![vec![1, -1, 4], vec![1, 1, 1]]);
+        assert!(matches!(missing, Err(TiledError::Io(_))));
+    }
+
+    #[test]
+    fn reads_tmj_maps() {
+        let map = r#"{
+            "width": 3, "height": 2, "tilewidth": 16, "tileheight": 16, "infinite": false,
+            "tilesets": [
+                {"firstgid": 1, "tiles": [
+                    {"id": 0, "properties": [{"name": "cost", "type": "int", "value": 1}]},
+                    {"id": 1, "properties": [{"name": "cost", "type": "string", "value": "-1"}]}
+                ]}
+            ],
+            "layers": [
+                {"type": "tilelayer", "data": [1, 1, 2147483650, 1, 3, 0]},
+                {"type": "tilelayer", "data": [2, 2, 2, 2, 2, 2], "visible": false},
+                {"type": "group", "layers": [
+                    {"type": "objectgroup", "objects": [
+                        {"x": 0, "y": 16, "width": 16, "height": 16},
+                        {"x": 16, "y": 0, "width": 16, "height": 16, "ellipse": true}
+                    ]}
+                ]},
+                {"type": "imagelayer", "image": "sky.png"}
+            ]
+        }"#;
+        let tiles = load_tmj(map, &TiledSettings::default()).unwrap();
+
+        assert_eq!(
+            rows(&tiles),
+            vec![vec![1, 1, -1], vec![BLOCKED_COST, 1, BLOCKED_COST]]
+        );
+    }
+
+    #[test]
+    fn rejects_other_encodings() {
+        let map = format!(
+            r#"{}
+<layer id="1" name="ground" width="3" height="2">
+  <data encoding="base64" compression="zlib">eJxjZGBgYAAAAA0AAw==</data>
+ </layer>"#,
+            TILESET
+        );
+        let result = load_tmx(&tmx_map(16, &map), &TiledSettings::default());
+        assert!(matches!(result, Err(TiledError::Unsupported(_))));
+
+        let map = r#"{
+            "width": 3, "height": 2, "tilewidth": 16, "tileheight": 16,
+            "layers": [{"type": "tilelayer", "encoding": "base64", "data": "AQAAAA=="}]
+        }"#;
+        let result = load_tmj(map, &TiledSettings::default());
+        assert!(matches!(result, Err(TiledError::Unsupported(_))));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        // A layer with fewer tiles than the map
+        let map = format!(
+            r#"{}
+<layer id="1" name="ground" width="2" height="2">
+  <data encoding="csv">1,1,
+1,1</data>
+ </layer>"#,
+            TILESET
+        );
+        let result = load_tmx(&tmx_map(16, &map), &TiledSettings::default());
+        assert!(matches!(result, Err(TiledError::Invalid(_))));
+
+        let map = format!("{}\n{}", TILESET, GROUND);
+        let result = load_tmx(&tmx_map(15, &map), &TiledSettings::default());
+        assert!(matches!(result, Err(TiledError::Unsupported(_))));
+
+        let result = load_tmx(
+            r#"<map width="3" tilewidth="16" tileheight="16"></map>"#,
+            &TiledSettings::default(),
+        );
+        assert!(matches!(result, Err(TiledError::Invalid(_))));
+
+        let result = load_tmx(
+            r#"<map width="3" height="2" tilewidth="16" tileheight="16" infinite="1"></map>"#,
+            &TiledSettings::default(),
+        );
+        assert!(matches!(result, Err(TiledError::Unsupported(_))));
+    }
+}
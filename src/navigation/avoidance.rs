@@ -0,0 +1,368 @@
+//! Local collision avoidance between navigators with Optimal Reciprocal Collision Avoidance.
+//!
+//! Each agent turns its neighbours into half-planes of velocities that don't collide with them
+//! within a time horizon, each agent taking half of the responsibility, then picks the velocity
+//! closest to the one it would like to have with a small linear program. Walls of the navmesh
+//! are static obstacles that agents take full responsibility for.
+//! See <https://gamma.cs.unc.edu/ORCA/> for the details.
+
+use bevy::{
+    prelude::{Entity, IVec2, Vec2},
+    utils::HashMap,
+};
+
+use super::TempNavmesh;
+
+/// Settings of the local avoidance, in [`NavigationSettings`](super::NavigationSettings)
+#[derive(Debug, Clone, Copy)]
+pub struct AvoidanceSettings {
+    pub enabled: bool,
+    /// Distance under which other agents are avoided
+    pub neighbour_distance: f32,
+    /// Closest agents taken into account
+    pub max_neighbours: usize,
+    /// How far ahead collisions with other agents are avoided, in seconds
+    pub time_horizon: f32,
+    /// How far ahead collisions with the walls of the navmesh are avoided, in seconds
+    pub obstacle_time_horizon: f32,
+}
+
+impl Default for AvoidanceSettings {
+    fn default() -> Self {
+        AvoidanceSettings {
+            enabled: true,
+            neighbour_distance: 20.0,
+            max_neighbours: 10,
+            time_horizon: 1.0,
+            obstacle_time_horizon: 0.25,
+        }
+    }
+}
+
+/// What the avoidance needs to know about an agent
+pub(crate) struct Agent {
+    pub entity: Entity,
+    pub position: Vec2,
+    /// Velocity during the last frame
+    pub velocity: Vec2,
+    /// Velocity given by the steering
+    pub preferred_velocity: Vec2,
+    pub radius: f32,
+    pub max_speed: f32,
+    pub navmesh: Option<Entity>,
+}
+
+/// Agents bucketed in square cells to find their neighbours
+pub(crate) struct AgentGrid {
+    cell_size: f32,
+    cells: HashMap<IVec2, Vec<usize>>,
+}
+
+impl AgentGrid {
+    pub(crate) fn new(agents: &[Agent], cell_size: f32) -> Self {
+        let cell_size = cell_size.max(1.0);
+        let mut cells: HashMap<IVec2, Vec<usize>> = HashMap::default();
+        for (index, agent) in agents.iter().enumerate() {
+            cells
+                .entry((agent.position / cell_size).floor().as_ivec2())
+                .or_default()
+                .push(index);
+        }
+        AgentGrid { cell_size, cells }
+    }
+
+    /// The `max` closest agents within `distance` of agent `index`, sorted by distance
+    pub(crate) fn neighbours(
+        &self,
+        agents: &[Agent],
+        index: usize,
+        distance: f32,
+        max: usize,
+    ) -> Vec<usize> {
+        let position = agents[index].position;
+        let min = ((position - distance) / self.cell_size).floor().as_ivec2();
+        let max_cell = ((position + distance) / self.cell_size).floor().as_ivec2();
+        let mut neighbours: Vec<(f32, usize)> = Vec::new();
+        for x in min.x..=max_cell.x {
+            for y in min.y..=max_cell.y {
+                let cell = match self.cells.get(&IVec2::new(x, y)) {
+                    Some(cell) => cell,
+                    None => continue,
+                };
+                for other in cell {
+                    let other_agent = &agents[*other];
+                    if *other == index || other_agent.navmesh != agents[index].navmesh {
+                        continue;
+                    }
+                    let distance_squared = other_agent.position.distance_squared(position);
+                    if distance_squared < distance * distance {
+                        neighbours.push((distance_squared, *other));
+                    }
+                }
+            }
+        }
+        neighbours.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+        neighbours.truncate(max);
+        neighbours.into_iter().map(|(_, other)| other).collect()
+    }
+}
+
+/// A directed line, the velocities on its left are allowed
+#[derive(Clone, Copy)]
+struct Line {
+    point: Vec2,
+    direction: Vec2,
+}
+
+const EPSILON: f32 = 0.00001;
+
+/// Velocity for agent `index` closest to its preferred velocity that avoids its neighbours and
+/// the walls of `temp`.
+pub(crate) fn avoiding_velocity(
+    agents: &[Agent],
+    index: usize,
+    neighbours: &[usize],
+    temp: Option<&TempNavmesh>,
+    settings: &AvoidanceSettings,
+    delta: f32,
+) -> Vec2 {
+    let agent = &agents[index];
+    let mut lines = Vec::with_capacity(neighbours.len());
+
+    if let Some(temp) = temp {
+        obstacle_lines(agent, temp, settings.obstacle_time_horizon, &mut lines);
+    }
+    let obstacle_line_count = lines.len();
+
+    let inverse_time_horizon = 1.0 / settings.time_horizon;
+    for other in neighbours {
+        let other = &agents[*other];
+        let relative_position = other.position - agent.position;
+        let relative_velocity = agent.velocity - other.velocity;
+        let distance_squared = relative_position.length_squared();
+        let combined_radius = agent.radius + other.radius;
+        let combined_radius_squared = combined_radius * combined_radius;
+
+        let (direction, u) = if distance_squared > combined_radius_squared {
+            // No collision yet, w goes from the center of the cut-off circle to the velocity
+            let w = relative_velocity - inverse_time_horizon * relative_position;
+            let w_length_squared = w.length_squared();
+            let dot = w.dot(relative_position);
+            if dot < 0.0 && dot * dot > combined_radius_squared * w_length_squared {
+                // Project on the cut-off circle
+                let w_length = w_length_squared.sqrt();
+                let unit_w = w / w_length;
+                (
+                    Vec2::new(unit_w.y, -unit_w.x),
+                    (combined_radius * inverse_time_horizon - w_length) * unit_w,
+                )
+            } else {
+                // Project on the closest leg of the cone
+                let leg = (distance_squared - combined_radius_squared).sqrt();
+                let direction = if relative_position.perp_dot(w) > 0.0 {
+                    Vec2::new(
+                        relative_position.x * leg - relative_position.y * combined_radius,
+                        relative_position.x * combined_radius + relative_position.y * leg,
+                    ) / distance_squared
+                } else {
+                    -Vec2::new(
+                        relative_position.x * leg + relative_position.y * combined_radius,
+                        -relative_position.x * combined_radius + relative_position.y * leg,
+                    ) / distance_squared
+                };
+                (
+                    direction,
+                    relative_velocity.dot(direction) * direction - relative_velocity,
+                )
+            }
+        } else {
+            // Already colliding, separate within this frame
+            let inverse_delta = 1.0 / delta;
+            let w = relative_velocity - inverse_delta * relative_position;
+            let w_length = w.length();
+            if w_length < EPSILON {
+                continue;
+            }
+            let unit_w = w / w_length;
+            (
+                Vec2::new(unit_w.y, -unit_w.x),
+                (combined_radius * inverse_delta - w_length) * unit_w,
+            )
+        };
+        lines.push(Line {
+            point: agent.velocity + 0.5 * u,
+            direction,
+        });
+    }
+
+    let mut velocity = Vec2::ZERO;
+    let failed = linear_program_2(
+        &lines,
+        agent.max_speed,
+        agent.preferred_velocity,
+        false,
+        &mut velocity,
+    );
+    if failed < lines.len() {
+        linear_program_3(
+            &lines,
+            obstacle_line_count,
+            failed,
+            agent.max_speed,
+            &mut velocity,
+        );
+    }
+    velocity
+}
+
+/// A half-plane for each wall close enough to be reached within the time horizon, forbidding the
+/// velocities that get closer than the radius of the agent.
+fn obstacle_lines(agent: &Agent, temp: &TempNavmesh, time_horizon: f32, lines: &mut Vec<Line>) {
+    let range = agent.max_speed * time_horizon + agent.radius;
+    for polygon in temp.polygon_grid.near(agent.position, range) {
+        for (a, b) in &temp.polygon_boundaries[polygon] {
+            let edge = *b - *a;
+            let along = ((agent.position - *a).dot(edge) / edge.length_squared().max(EPSILON))
+                .clamp(0.0, 1.0);
+            let toward = *a + edge * along - agent.position;
+            let distance = toward.length();
+            if distance < EPSILON || distance > range {
+                continue;
+            }
+            let unit = toward / distance;
+            lines.push(Line {
+                point: unit * (distance - agent.radius) / time_horizon,
+                direction: Vec2::new(-unit.y, unit.x),
+            });
+        }
+    }
+}
+
+/// Solves the program on the line `line`, within the circle of radius `radius` and the previous
+/// lines. Returns false if there is no solution.
+fn linear_program_1(
+    lines: &[Line],
+    line: usize,
+    radius: f32,
+    optimal: Vec2,
+    optimize_direction: bool,
+    result: &mut Vec2,
+) -> bool {
+    let current = lines[line];
+    let dot = current.point.dot(current.direction);
+    let discriminant = dot * dot + radius * radius - current.point.length_squared();
+    if discriminant < 0.0 {
+        // The circle fully invalidates the line
+        return false;
+    }
+    let discriminant = discriminant.sqrt();
+    let mut t_left = -dot - discriminant;
+    let mut t_right = -dot + discriminant;
+
+    for previous in &lines[..line] {
+        let denominator = current.direction.perp_dot(previous.direction);
+        let numerator = previous.direction.perp_dot(current.point - previous.point);
+        if denominator.abs() <= EPSILON {
+            // Parallel lines
+            if numerator < 0.0 {
+                return false;
+            }
+            continue;
+        }
+        let t = numerator / denominator;
+        if denominator >= 0.0 {
+            t_right = t_right.min(t);
+        } else {
+            t_left = t_left.max(t);
+        }
+        if t_left > t_right {
+            return false;
+        }
+    }
+
+    let t = if optimize_direction {
+        if optimal.dot(current.direction) > 0.0 {
+            t_right
+        } else {
+            t_left
+        }
+    } else {
+        current
+            .direction
+            .dot(optimal - current.point)
+            .clamp(t_left, t_right)
+    };
+    *result = current.point + t * current.direction;
+    true
+}
+
+/// Finds the velocity closest to `optimal` within the circle and on the left of all lines.
+/// Returns the index of the line where it failed, or the number of lines on success.
+fn linear_program_2(
+    lines: &[Line],
+    radius: f32,
+    optimal: Vec2,
+    optimize_direction: bool,
+    result: &mut Vec2,
+) -> usize {
+    *result = if optimize_direction {
+        // `optimal` is a unit direction in this case
+        optimal * radius
+    } else {
+        optimal.clamp_length_max(radius)
+    };
+    for (index, line) in lines.iter().enumerate() {
+        if line.direction.perp_dot(line.point - *result) > 0.0 {
+            let previous = *result;
+            if !linear_program_1(lines, index, radius, optimal, optimize_direction, result) {
+                *result = previous;
+                return index;
+            }
+        }
+    }
+    lines.len()
+}
+
+/// When the agents are too dense for a solution, finds the velocity that least violates the
+/// lines from agents, keeping the obstacle lines as hard constraints.
+fn linear_program_3(
+    lines: &[Line],
+    obstacle_line_count: usize,
+    begin: usize,
+    radius: f32,
+    result: &mut Vec2,
+) {
+    let mut distance = 0.0;
+    for index in begin..lines.len() {
+        let line = lines[index];
+        if line.direction.perp_dot(line.point - *result) <= distance {
+            continue;
+        }
+        let mut projected: Vec<Line> = lines[..obstacle_line_count].to_vec();
+        for other in &lines[obstacle_line_count..index] {
+            let determinant = line.direction.perp_dot(other.direction);
+            let point = if determinant.abs() <= EPSILON {
+                if line.direction.dot(other.direction) > 0.0 {
+                    // Same direction
+                    continue;
+                }
+                0.5 * (line.point + other.point)
+            } else {
+                line.point
+                    + line.direction
+                        * (other.direction.perp_dot(line.point - other.point) / determinant)
+            };
+            projected.push(Line {
+                point,
+                direction: (other.direction - line.direction).normalize_or_zero(),
+            });
+        }
+        let previous = *result;
+        let optimal = Vec2::new(-line.direction.y, line.direction.x);
+        if linear_program_2(&projected, radius, optimal, true, result) < projected.len() {
+            // Can only happen because of floating point errors, keep the previous result
+            *result = previous;
+        }
+        distance = line.direction.perp_dot(line.point - *result);
+    }
+}
@@ -0,0 +1,344 @@
+//! Reuse of the paths computed recently, and of the searches in flight, for identical requests.
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    prelude::{Entity, IVec2, Vec2},
+    utils::HashMap,
+};
+
+use super::{PathRequest, PathRequestId, TempNavmesh};
+
+/// Requests with the same key get the same path: same navmesh, same polygons and ends in the same
+/// cells of `NavigationSettings::path_cache_cell` units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct PathKey {
+    navmesh: Entity,
+    version: u64,
+    from_polygon: Option<usize>,
+    to_polygon: Option<usize>,
+    from: IVec2,
+    to: IVec2,
+}
+
+impl PathKey {
+    /// Key of a request whose ends are already on `temp`
+    pub(crate) fn new(
+        navmesh: Entity,
+        temp: &TempNavmesh,
+        from: Vec2,
+        to: Vec2,
+        cell: f32,
+    ) -> Self {
+        let cell = cell.max(f32::EPSILON);
+        PathKey {
+            navmesh,
+            version: temp.version,
+            from_polygon: temp.polygon_at(from),
+            to_polygon: temp.polygon_at(to),
+            from: (from / cell).floor().as_ivec2(),
+            to: (to / cell).floor().as_ivec2(),
+        }
+    }
+}
+
+struct CachedPath {
+    path: Option<Vec<Vec2>>,
+    length: f32,
+    /// Key of the entry in `PathCache::recent`
+    last_used: u64,
+}
+
+/// Recent paths with least recently used eviction, and the requests waiting for an identical
+/// request being computed.
+#[derive(Default)]
+pub(crate) struct PathCache {
+    paths: HashMap<PathKey, CachedPath>,
+    /// Keys of `paths` from the least to the most recently used
+    recent: BTreeMap<u64, PathKey>,
+    uses: u64,
+    /// Request computing the path of each key
+    in_flight: HashMap<PathKey, PathRequestId>,
+    /// Key of each request in `in_flight`, with the requests waiting for its result
+    leaders: HashMap<PathRequestId, (PathKey, Vec<PathRequest>)>,
+}
+
+impl PathCache {
+    /// Path and length for `key`, if it's known
+    pub(crate) fn get(&mut self, key: &PathKey) -> Option<(Option<Vec<Vec2>>, f32)> {
+        let cached = self.paths.get_mut(key)?;
+        self.recent.remove(&cached.last_used);
+        self.uses += 1;
+        cached.last_used = self.uses;
+        self.recent.insert(self.uses, *key);
+        Some((cached.path.clone(), cached.length))
+    }
+
+    /// Makes `request` wait for the result of the identical request in flight, if there is one.
+    pub(crate) fn join(&mut self, key: &PathKey, request: &PathRequest) -> bool {
+        let leader = match self.in_flight.get(key) {
+            Some(leader) => leader,
+            None => return false,
+        };
+        if let Some((_, followers)) = self.leaders.get_mut(leader) {
+            followers.push(request.clone());
+        }
+        true
+    }
+
+    /// Records that `id` computes the path of `key`
+    pub(crate) fn start(&mut self, key: PathKey, id: PathRequestId) {
+        self.in_flight.insert(key, id);
+        self.leaders.insert(id, (key, Vec::new()));
+    }
+
+    /// Stores the result of request `id`, returning the requests that were waiting for it.
+    pub(crate) fn finish(
+        &mut self,
+        id: PathRequestId,
+        path: &Option<Vec<Vec2>>,
+        length: f32,
+        capacity: usize,
+    ) -> Vec<PathRequest> {
+        let (key, followers) = match self.leaders.remove(&id) {
+            Some(leader) => leader,
+            None => return Vec::new(),
+        };
+        // The key was invalidated while the path was computed
+        if self.in_flight.get(&key) != Some(&id) {
+            return followers;
+        }
+        self.in_flight.remove(&key);
+        if capacity == 0 {
+            return followers;
+        }
+
+        while self.paths.len() >= capacity {
+            let oldest = match self.recent.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(evicted) = self.recent.remove(&oldest) {
+                self.paths.remove(&evicted);
+            }
+        }
+        self.uses += 1;
+        if let Some(previous) = self.paths.insert(
+            key,
+            CachedPath {
+                path: path.clone(),
+                length,
+                last_used: self.uses,
+            },
+        ) {
+            self.recent.remove(&previous.last_used);
+        }
+        self.recent.insert(self.uses, key);
+        followers
+    }
+
+    /// Forgets the cancelled request `id`, returning the requests that were waiting for it so
+    /// that they can be queued again.
+    pub(crate) fn abandon(&mut self, id: PathRequestId) -> Vec<PathRequest> {
+        let (key, followers) = match self.leaders.remove(&id) {
+            Some(leader) => leader,
+            None => return Vec::new(),
+        };
+        if self.in_flight.get(&key) == Some(&id) {
+            self.in_flight.remove(&key);
+        }
+        followers
+    }
+
+    /// Drops the waiting requests whose requester matches `predicate`
+    pub(crate) fn cancel_followers(&mut self, predicate: impl Fn(Entity) -> bool) {
+        for (_, followers) in self.leaders.values_mut() {
+            followers.retain(
+                |request| !matches!(request.requester, Some(requester) if predicate(requester)),
+            );
+        }
+    }
+
+    /// Forgets the paths on `navmesh`, after it changed
+    pub(crate) fn invalidate(&mut self, navmesh: Entity) {
+        let recent = &mut self.recent;
+        self.paths.retain(|key, cached| {
+            let keep = key.navmesh != navmesh;
+            if !keep {
+                recent.remove(&cached.last_used);
+            }
+            keep
+        });
+        self.in_flight.retain(|key, _| key.navmesh != navmesh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::navigation::{PathPriority, PathRequestIds};
+
+    fn key(navmesh: u32, to: i32) -> PathKey {
+        PathKey {
+            navmesh: Entity::from_raw(navmesh),
+            version: 1,
+            from_polygon: Some(0),
+            to_polygon: Some(1),
+            from: IVec2::ZERO,
+            to: IVec2::new(to, 0),
+        }
+    }
+
+    fn request(ids: &mut PathRequestIds, requester: u32) -> PathRequest {
+        PathRequest {
+            id: ids.next_id(),
+            priority: PathPriority::Idle,
+            from: Vec2::ZERO,
+            to: Vec2::X,
+            navmesh: None,
+            requester: Some(Entity::from_raw(requester)),
+        }
+    }
+
+    /// Computes the path of `key` with a new request, as if nobody was waiting for it
+    fn compute(cache: &mut PathCache, ids: &mut PathRequestIds, key: PathKey, capacity: usize) {
+        let id = ids.next_id();
+        cache.start(key, id);
+        let followers = cache.finish(id, &Some(vec![Vec2::X]), 1.0, capacity);
+        assert!(followers.is_empty());
+    }
+
+    #[test]
+    fn finished_paths_are_cached() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        assert!(cache.get(&key(0, 1)).is_none());
+
+        let id = ids.next_id();
+        cache.start(key(0, 1), id);
+        assert!(cache.get(&key(0, 1)).is_none());
+        cache.finish(id, &Some(vec![Vec2::ONE]), 2.0, 8);
+
+        assert_eq!(cache.get(&key(0, 1)), Some((Some(vec![Vec2::ONE]), 2.0)));
+        assert!(cache.get(&key(0, 2)).is_none());
+    }
+
+    #[test]
+    fn missing_paths_are_cached() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        let id = ids.next_id();
+        cache.start(key(0, 1), id);
+        cache.finish(id, &None, 0.0, 8);
+
+        assert_eq!(cache.get(&key(0, 1)), Some((None, 0.0)));
+    }
+
+    #[test]
+    fn least_recently_used_path_is_evicted() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        compute(&mut cache, &mut ids, key(0, 1), 2);
+        compute(&mut cache, &mut ids, key(0, 2), 2);
+        // Used after the second one, which becomes the oldest
+        assert!(cache.get(&key(0, 1)).is_some());
+        compute(&mut cache, &mut ids, key(0, 3), 2);
+
+        assert!(cache.get(&key(0, 1)).is_some());
+        assert!(cache.get(&key(0, 2)).is_none());
+        assert!(cache.get(&key(0, 3)).is_some());
+        assert_eq!(cache.paths.len(), cache.recent.len());
+    }
+
+    #[test]
+    fn nothing_is_cached_without_capacity() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        compute(&mut cache, &mut ids, key(0, 1), 0);
+
+        assert!(cache.get(&key(0, 1)).is_none());
+        assert!(cache.recent.is_empty());
+    }
+
+    #[test]
+    fn followers_get_the_result_of_the_leader() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        let follower = request(&mut ids, 1);
+        assert!(!cache.join(&key(0, 1), &follower));
+
+        let id = ids.next_id();
+        cache.start(key(0, 1), id);
+        assert!(cache.join(&key(0, 1), &follower));
+        assert!(!cache.join(&key(0, 2), &follower));
+
+        let followers = cache.finish(id, &Some(vec![Vec2::X]), 1.0, 8);
+        assert_eq!(followers.len(), 1);
+        assert_eq!(followers[0].id, follower.id);
+        // Later requests are answered by the cache instead
+        assert!(!cache.join(&key(0, 1), &follower));
+    }
+
+    #[test]
+    fn abandoned_leader_releases_its_followers() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        let id = ids.next_id();
+        cache.start(key(0, 1), id);
+        let follower = request(&mut ids, 1);
+        assert!(cache.join(&key(0, 1), &follower));
+
+        let followers = cache.abandon(id);
+        assert_eq!(followers.len(), 1);
+        assert_eq!(followers[0].id, follower.id);
+        assert!(!cache.join(&key(0, 1), &follower));
+        assert!(cache.abandon(id).is_empty());
+        assert!(cache.get(&key(0, 1)).is_none());
+    }
+
+    #[test]
+    fn cancelled_followers_are_dropped() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        let id = ids.next_id();
+        cache.start(key(0, 1), id);
+        let kept = request(&mut ids, 1);
+        assert!(cache.join(&key(0, 1), &kept));
+        assert!(cache.join(&key(0, 1), &request(&mut ids, 2)));
+
+        cache.cancel_followers(|requester| requester == Entity::from_raw(2));
+        let followers = cache.finish(id, &None, 0.0, 8);
+        assert_eq!(followers.len(), 1);
+        assert_eq!(followers[0].id, kept.id);
+    }
+
+    #[test]
+    fn invalidate_forgets_the_paths_of_the_navmesh() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        compute(&mut cache, &mut ids, key(0, 1), 8);
+        compute(&mut cache, &mut ids, key(1, 1), 8);
+
+        cache.invalidate(Entity::from_raw(0));
+        assert!(cache.get(&key(0, 1)).is_none());
+        assert!(cache.get(&key(1, 1)).is_some());
+        assert_eq!(cache.paths.len(), cache.recent.len());
+    }
+
+    #[test]
+    fn paths_finished_after_invalidate_are_not_cached() {
+        let mut cache = PathCache::default();
+        let mut ids = PathRequestIds::default();
+        let id = ids.next_id();
+        cache.start(key(0, 1), id);
+        let follower = request(&mut ids, 1);
+        assert!(cache.join(&key(0, 1), &follower));
+
+        cache.invalidate(Entity::from_raw(0));
+        // A new request computes the path again instead of waiting for the stale one
+        assert!(!cache.join(&key(0, 1), &follower));
+        let followers = cache.finish(id, &Some(vec![Vec2::X]), 1.0, 8);
+        assert_eq!(followers.len(), 1);
+        assert!(cache.get(&key(0, 1)).is_none());
+    }
+}
@@ -0,0 +1,245 @@
+//! Flow fields, to send many agents to the same place without a path search for each of them.
+//!
+//! The distance to the target is computed once for every polygon of the navmesh, agents with a
+//! [`FlowTarget`] then go toward the neighbouring polygon closest to the target.
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use polyanya::Mesh as PAMesh;
+
+use super::{
+    cache::PathCache,
+    request::{cancel_requesters, PathQueue, PendingPaths},
+    FindingPath, NavigationSettings, NavmeshId, Path, PathPriority, Target, TempNavmesh,
+};
+
+/// Sends an agent to `target` with the flow field shared by all the agents going there, instead
+/// of computing its own [`Path`]. Removed when the agent has arrived.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct FlowTarget {
+    pub target: Vec2,
+    /// Priority of the path searched instead when the field can't bring the agent to its target
+    pub priority: PathPriority,
+}
+
+/// Identifies the flow field of a target, targets that round to the same unit cell share a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowFieldKey {
+    pub navmesh: Entity,
+    target: IVec2,
+}
+
+impl FlowFieldKey {
+    pub fn new(navmesh: Entity, target: Vec2) -> Self {
+        FlowFieldKey {
+            navmesh,
+            target: target.round().as_ivec2(),
+        }
+    }
+}
+
+/// Distance to a target and direction toward it from every polygon of a navmesh.
+pub struct FlowField {
+    /// The target, moved on the navmesh if it was outside
+    pub target: Vec2,
+    target_polygon: usize,
+    /// Distance to the target going through the centers of the polygons, infinite for the
+    /// polygons it can't be reached from
+    pub distances: Vec<f32>,
+    /// Edge to cross to get closer to the target, and the center of the polygon behind it
+    portals: Vec<Option<(Vec2, Vec2, Vec2)>>,
+}
+
+impl FlowField {
+    /// Integrates the distances from the target over the polygons of the navmesh. Returns `None`
+    /// if the target isn't within `snap_radius` of the navmesh.
+    pub fn new(temp: &TempNavmesh, target: Vec2, snap_radius: f32) -> Option<Self> {
+        let (target_polygon, target) = match temp.polygon_at(target) {
+            Some(polygon) => (polygon, target),
+            None => temp.nearest_point_where(target, snap_radius, |_| true)?,
+        };
+        let mesh = &temp.debug_pa_navmesh;
+        let centers = polygon_centers(mesh);
+        let neighbours = polygon_portals(mesh);
+
+        let mut distances = vec![f32::INFINITY; mesh.polygons.len()];
+        let mut portals = vec![None; mesh.polygons.len()];
+        distances[target_polygon] = 0.0;
+        // The bits of positive floats are ordered like the floats
+        let mut open = BinaryHeap::new();
+        open.push(Reverse((0.0f32.to_bits(), target_polygon)));
+        while let Some(Reverse((distance, polygon))) = open.pop() {
+            let distance = f32::from_bits(distance);
+            if distance > distances[polygon] {
+                continue;
+            }
+            for (neighbour, a, b) in &neighbours[polygon] {
+                let from_target = if polygon == target_polygon {
+                    target.distance(centers[*neighbour])
+                } else {
+                    distance + centers[polygon].distance(centers[*neighbour])
+                };
+                if from_target < distances[*neighbour] {
+                    distances[*neighbour] = from_target;
+                    portals[*neighbour] = Some((*a, *b, centers[polygon]));
+                    open.push(Reverse((from_target.to_bits(), *neighbour)));
+                }
+            }
+        }
+
+        Some(FlowField {
+            target,
+            target_polygon,
+            distances,
+            portals,
+        })
+    }
+
+    /// Direction toward the target from `position` in `polygon`, for an agent of radius
+    /// `radius`. Returns `None` when the target can't be reached from this polygon.
+    pub fn direction(&self, polygon: usize, position: Vec2, radius: f32) -> Option<Vec2> {
+        if polygon == self.target_polygon {
+            return Some((self.target - position).normalize_or_zero());
+        }
+        let (a, b, next_center) = self.portals.get(polygon).copied().flatten()?;
+        // Aim inside the edge so that the agent doesn't clip the corners
+        let length = a.distance(b);
+        let inset = if length > f32::EPSILON {
+            radius.min(length / 2.0) / length
+        } else {
+            0.0
+        };
+        let (a, b) = (a.lerp(b, inset), b.lerp(a, inset));
+        let edge = b - a;
+        let along =
+            ((position - a).dot(edge) / edge.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+        let toward = a + edge * along - position;
+        if toward.length_squared() < 1.0 {
+            // On the edge, go through it
+            return Some((next_center - (a + b) / 2.0).normalize_or_zero());
+        }
+        Some(toward.normalize())
+    }
+
+    /// If the target is reached in the polygon, so the agent should slow down when arriving
+    pub fn is_target_polygon(&self, polygon: usize) -> bool {
+        polygon == self.target_polygon
+    }
+}
+
+/// Flow fields of the targets of the agents with a [`FlowTarget`]
+#[derive(Default)]
+pub struct FlowFields {
+    pub fields: HashMap<FlowFieldKey, FlowField>,
+}
+
+impl FlowFields {
+    pub fn get(&self, navmesh: Entity, target: Vec2) -> Option<&FlowField> {
+        self.fields.get(&FlowFieldKey::new(navmesh, target))
+    }
+}
+
+fn polygon_centers(mesh: &PAMesh) -> Vec<Vec2> {
+    mesh.polygons
+        .iter()
+        .map(|polygon| {
+            polygon
+                .vertices
+                .iter()
+                .map(|vertex| mesh.vertices[*vertex as usize].coords)
+                .sum::<Vec2>()
+                / polygon.vertices.len().max(1) as f32
+        })
+        .collect()
+}
+
+/// Neighbours of each polygon with the edge they share
+fn polygon_portals(mesh: &PAMesh) -> Vec<Vec<(usize, Vec2, Vec2)>> {
+    let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::default();
+    for (polygon_idx, polygon) in mesh.polygons.iter().enumerate() {
+        let count = polygon.vertices.len();
+        for i in 0..count {
+            let a = polygon.vertices[i] as usize;
+            let b = polygon.vertices[(i + 1) % count] as usize;
+            edges
+                .entry((a.min(b), a.max(b)))
+                .or_default()
+                .push(polygon_idx);
+        }
+    }
+    let mut portals = vec![Vec::new(); mesh.polygons.len()];
+    for ((a, b), polygons) in edges {
+        let (a, b) = (mesh.vertices[a].coords, mesh.vertices[b].coords);
+        for first in &polygons {
+            for second in &polygons {
+                if first != second {
+                    portals[*first].push((*second, a, b));
+                }
+            }
+        }
+    }
+    portals
+}
+
+/// Builds the flow fields of new targets, drops the ones nobody uses anymore and rebuilds them
+/// when their navmesh changes.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_flow_fields(
+    mut commands: Commands,
+    new_targets: Query<Entity, Changed<FlowTarget>>,
+    agents: Query<(Entity, &FlowTarget, &NavmeshId)>,
+    changed_mesh_q: Query<Entity, Changed<TempNavmesh>>,
+    mesh_q: Query<&TempNavmesh>,
+    settings: Res<NavigationSettings>,
+    mut flow_fields: ResMut<FlowFields>,
+    mut pending: ResMut<PendingPaths>,
+    mut cache: ResMut<PathCache>,
+    mut queue: ResMut<PathQueue>,
+) {
+    // The flow field replaces the path of the agent, and the search for it
+    let mut searching = HashSet::new();
+    new_targets.for_each(|entity| {
+        commands
+            .entity(entity)
+            .remove::<Target>()
+            .remove::<Path>()
+            .remove::<FindingPath>();
+        searching.insert(entity);
+    });
+    if !searching.is_empty() {
+        cancel_requesters(&mut pending, &mut cache, &mut queue, &searching);
+    }
+
+    for changed in changed_mesh_q.iter() {
+        flow_fields.fields.retain(|key, _| key.navmesh != changed);
+    }
+
+    let mut used = HashSet::new();
+    let mut unreachable = HashSet::new();
+    agents.for_each(|(entity, flow_target, navmesh_id)| {
+        let key = FlowFieldKey::new(navmesh_id.0, flow_target.target);
+        used.insert(key);
+        if !flow_fields.fields.contains_key(&key) && !unreachable.contains(&key) {
+            let field = mesh_q
+                .get(navmesh_id.0)
+                .ok()
+                .and_then(|temp| FlowField::new(temp, flow_target.target, settings.snap_radius));
+            match field {
+                Some(field) => {
+                    flow_fields.fields.insert(key, field);
+                }
+                None => {
+                    unreachable.insert(key);
+                }
+            }
+        }
+        if unreachable.contains(&key) {
+            commands.entity(entity).remove::<FlowTarget>();
+        }
+    });
+    flow_fields.fields.retain(|key, _| used.contains(key));
+}
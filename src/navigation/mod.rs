@@ -0,0 +1,299 @@
+//! Navigation on navmeshes generated from tilemaps, independent of the demo game.
+//!
+//! Add [`NavigationPlugin`] and insert a [`TempNavmesh`] on an entity, for example with
+//! [`navmesh_from_tilemap`]. Agents need a [`Navigator`] and the [`NavmeshId`] of that entity.
+//! Inserting a [`Target`] on an agent computes a [`Path`] to it, which the agent then follows.
+//! One-off paths can be computed without an agent by sending a [`PathRequest`].
+//! Paths going through a part of the navmesh that changed are computed again.
+//! Large groups sent to the same place can share a flow field instead, with a [`FlowTarget`].
+//! Agents avoid each other and the walls of the navmesh while moving, see [`AvoidanceSettings`].
+
+mod avoidance;
+mod cache;
+mod flow_field;
+mod navmesh;
+mod query;
+mod replan;
+mod request;
+mod steering;
+
+use bevy::{ecs::entity::Entities, math::Vec3Swizzles, prelude::*, utils::HashMap};
+use bevy_pathmesh::PathmeshPlugin;
+
+pub use avoidance::AvoidanceSettings;
+pub use flow_field::{FlowField, FlowFieldKey, FlowFields, FlowTarget};
+pub use navmesh::{
+    build_square_navmesh, build_square_navmesh_with_progress, navmesh_from_tilemap, Connections,
+    NavmeshInput, TempNavmesh, TileCost,
+};
+pub use query::{OffMeshPolicy, PolygonGrid};
+pub use request::{
+    is_on_screen, NavigationStats, PathPriority, PathRequest, PathRequestId, PathRequestIds,
+    PathResponse,
+};
+
+use cache::PathCache;
+use flow_field::build_flow_fields;
+use replan::replan_paths;
+use request::{poll_path_requests, process_path_requests, PathQueue, PendingPaths};
+use steering::move_navigator;
+
+pub struct NavigationPlugin;
+
+impl Plugin for NavigationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(PathmeshPlugin)
+            .init_resource::<NavigationSettings>()
+            .init_resource::<NavigationStats>()
+            .init_resource::<PathRequestIds>()
+            .init_resource::<PathQueue>()
+            .init_resource::<PendingPaths>()
+            .init_resource::<PathCache>()
+            .init_resource::<FlowFields>()
+            .init_resource::<LatestRequests>()
+            .add_event::<PathRequest>()
+            .add_event::<PathResponse>()
+            .add_system(compute_paths.before(process_path_requests))
+            .add_system(process_path_requests)
+            .add_system(poll_path_requests.after(process_path_requests))
+            // Responses are sent during the update, the `FindingPath` of their requester is only
+            // inserted at the end of it
+            .add_system_to_stage(CoreStage::PostUpdate, poll_path_tasks)
+            .add_system(replan_paths)
+            .add_system(build_flow_fields.before(move_navigator))
+            .add_system(move_navigator);
+    }
+}
+
+/// How paths are computed on the async compute pool
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TaskMode {
+    Async,
+    Blocking,
+    /// Several paths on the same navmesh per task, see `NavigationSettings::batch_size`
+    Batched,
+}
+
+/// Settings of [`NavigationPlugin`]
+pub struct NavigationSettings {
+    pub task_mode: TaskMode,
+    /// Path tasks running at the same time, the other requests wait in a queue
+    pub max_paths_in_flight: usize,
+    /// Path tasks started each frame
+    pub max_path_starts_per_frame: usize,
+    /// Paths computed by a single task with `TaskMode::Batched`
+    pub batch_size: usize,
+    /// Paths kept to answer identical requests, the least recently used are dropped first
+    pub path_cache_size: usize,
+    /// Requests whose ends are in the same cells of this size, and in the same polygons, get the
+    /// same path
+    pub path_cache_cell: f32,
+    /// What to do with starts and targets outside of the navmesh
+    pub off_mesh_policy: OffMeshPolicy,
+    /// How far starts and targets can be moved to be on the navmesh
+    pub snap_radius: f32,
+    /// Distance under which a waypoint before the end of the path is reached
+    pub waypoint_radius: f32,
+    /// Distance to the next waypoint under which agents cut the corner when they can go straight
+    /// to the waypoint after it
+    pub corner_look_ahead: f32,
+    /// Agents outside of their navmesh are despawned when no path to their target is found
+    pub despawn_off_mesh: bool,
+    /// Distance to a [`FlowTarget`] under which an agent has arrived, several agents can't be at
+    /// the same place
+    pub flow_arrival_radius: f32,
+    /// Seconds an agent can go without getting closer to its goal before giving up, when other
+    /// agents or walls are in the way
+    pub stall_timeout: f32,
+    pub avoidance: AvoidanceSettings,
+}
+
+impl Default for NavigationSettings {
+    fn default() -> Self {
+        NavigationSettings {
+            task_mode: TaskMode::Blocking,
+            max_paths_in_flight: 1024,
+            max_path_starts_per_frame: 256,
+            batch_size: 32,
+            path_cache_size: 4096,
+            path_cache_cell: 2.0,
+            off_mesh_policy: OffMeshPolicy::Snap,
+            snap_radius: 64.0,
+            waypoint_radius: 4.0,
+            corner_look_ahead: 24.0,
+            despawn_off_mesh: true,
+            flow_arrival_radius: 16.0,
+            stall_timeout: 3.0,
+            avoidance: AvoidanceSettings::default(),
+        }
+    }
+}
+
+/// The navmesh entity an agent moves on, several navmeshes can exist at the same time.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NavmeshId(pub Entity);
+
+/// An agent steering along its [`Path`].
+#[derive(Component)]
+pub struct Navigator {
+    /// Maximum speed
+    pub speed: f32,
+    /// Maximum change of velocity per second
+    pub max_acceleration: f32,
+    /// Maximum rotation of the velocity per second, in radians
+    pub max_turn_rate: f32,
+    /// Distance to the target under which the agent slows down
+    pub arrival_radius: f32,
+    /// Radius kept clear of other navigators and walls
+    pub radius: f32,
+    /// Current velocity
+    pub velocity: Vec2,
+    /// Waypoint or polygon the agent is heading through, and the closest it got to its goal there
+    pub(crate) progress: (usize, f32),
+    /// Seconds since the agent last got closer to its goal
+    pub(crate) stalled: f32,
+}
+
+impl Navigator {
+    /// A navigator reaching its maximum speed in a quarter of a second
+    pub fn new(speed: f32) -> Self {
+        Navigator {
+            speed,
+            max_acceleration: speed * 4.0,
+            max_turn_rate: std::f32::consts::TAU,
+            arrival_radius: speed * 0.5,
+            radius: 2.5,
+            velocity: Vec2::ZERO,
+            progress: (0, f32::INFINITY),
+            stalled: 0.0,
+        }
+    }
+}
+
+/// Where the agent wants to go, the path is computed when this changes.
+#[derive(Component)]
+pub struct Target {
+    pub target: Vec2,
+    pub priority: PathPriority,
+}
+
+/// Points left to reach the [`Target`], removed with the target when it's reached.
+#[derive(Component)]
+pub struct Path {
+    pub path: Vec<Vec2>,
+    /// `TempNavmesh::version` of the navmesh the path was computed on
+    pub navmesh_version: u64,
+    /// Polygons of that navmesh the path goes through, sorted
+    pub polygons: Vec<usize>,
+}
+
+/// Added while the path to the [`Target`] is computed.
+#[derive(Component)]
+pub struct FindingPath(PathRequestId);
+
+/// Latest request sent for the [`Target`] of each agent, known before its [`FindingPath`] is
+/// inserted
+#[derive(Default)]
+pub(crate) struct LatestRequests(pub(crate) HashMap<Entity, PathRequestId>);
+
+/// Drops the queued and running path searches, the cached paths and the flow fields, for when
+/// every navmesh is despawned. Searches that were dropped get no [`PathResponse`].
+pub(crate) fn clear_navigation(
+    mut queue: ResMut<PathQueue>,
+    mut pending: ResMut<PendingPaths>,
+    mut cache: ResMut<PathCache>,
+    mut flow_fields: ResMut<FlowFields>,
+    mut latest: ResMut<LatestRequests>,
+    mut stats: ResMut<NavigationStats>,
+) {
+    // Dropping the tasks cancels them
+    *queue = PathQueue::default();
+    *pending = PendingPaths::default();
+    *cache = PathCache::default();
+    *flow_fields = FlowFields::default();
+    *latest = LatestRequests::default();
+    *stats = NavigationStats::default();
+}
+
+fn compute_paths(
+    mut commands: Commands,
+    with_target: Query<(Entity, &Target, &Transform, &NavmeshId), Changed<Target>>,
+    mut request_ids: ResMut<PathRequestIds>,
+    mut latest: ResMut<LatestRequests>,
+    mut requests: EventWriter<PathRequest>,
+) {
+    with_target.for_each(|(entity, target, transform, navmesh_id)| {
+        let id = request_ids.next_id();
+        latest.0.insert(entity, id);
+        requests.send(PathRequest {
+            id,
+            priority: target.priority,
+            from: transform.translation.truncate(),
+            to: target.target,
+            navmesh: Some(navmesh_id.0),
+            requester: Some(entity),
+        });
+        commands
+            .entity(entity)
+            .insert(FindingPath(id))
+            .remove::<FlowTarget>();
+    });
+}
+
+fn poll_path_tasks(
+    mut commands: Commands,
+    mut responses: EventReader<PathResponse>,
+    computing: Query<(Option<&FindingPath>, &Transform, &NavmeshId), With<Target>>,
+    mut latest: ResMut<LatestRequests>,
+    settings: Res<NavigationSettings>,
+    mesh_query: Query<&TempNavmesh>,
+    entities: &Entities,
+) {
+    for response in responses.iter() {
+        let entity = match response.requester {
+            Some(entity) => entity,
+            None => continue,
+        };
+        let (transform, navmesh_id) = match computing.get(entity) {
+            // Responses to an older target are dropped, the latest one is kept even if its
+            // `FindingPath` isn't there yet
+            Ok((finding, transform, navmesh_id))
+                if latest.0.get(&entity) == Some(&response.id)
+                    || finding.map(|finding| finding.0) == Some(response.id) =>
+            {
+                (transform, navmesh_id)
+            }
+            _ => continue,
+        };
+        latest.0.remove(&entity);
+        if let Some(path) = &response.path {
+            let path = match mesh_query.get(navmesh_id.0) {
+                Ok(temp) => Path::on_navmesh(path.clone(), transform.translation.xy(), temp),
+                Err(_) => Path {
+                    path: path.clone(),
+                    navmesh_version: 0,
+                    polygons: Vec::new(),
+                },
+            };
+            commands.entity(entity).insert(path).remove::<FindingPath>();
+        } else {
+            let in_mesh = mesh_query
+                .get(navmesh_id.0)
+                .map(|temp| temp.navmesh.is_in_mesh(transform.translation.xy()))
+                .unwrap_or_default();
+            if !in_mesh && settings.despawn_off_mesh {
+                commands.entity(entity).despawn();
+                continue;
+            }
+
+            commands
+                .entity(entity)
+                .remove::<FindingPath>()
+                .remove::<Target>();
+        }
+    }
+    // Agents that were despawned or lost their target won't get an answer anymore
+    latest
+        .0
+        .retain(|entity, _| entities.contains(*entity) && computing.get(*entity).is_ok());
+}
@@ -0,0 +1,293 @@
+//! Navmesh generation from tilemaps, where each tile has a [`TileCost`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bevy::{
+    prelude::{debug, info, Component, IVec2, Query, Transform, Vec2},
+    utils::{HashMap, HashSet, Instant},
+};
+use indexmap::IndexMap;
+use polyanya::{Mesh as PAMesh, Polygon as PAPoly, Vertex as PAVertex};
+
+use bevy_ecs_tilemap::{
+    prelude::{TilemapGridSize, TilemapType},
+    tiles::{TilePos, TileStorage},
+};
+use bevy_pathmesh::PathMesh;
+
+use super::query::{boundary_edges, cumulative_areas, polygon_components, PolygonGrid};
+
+/// Cost of walking on a tile, tiles with a cost below 1 aren't walkable.
+#[derive(Component)]
+pub struct TileCost(pub i8);
+
+impl Default for TileCost {
+    fn default() -> Self {
+        TileCost(1)
+    }
+}
+
+pub struct Connections {
+    pub connection_indices: Vec<isize>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Connections {
+            connection_indices: Vec::new(),
+        }
+    }
+}
+
+struct Polys {
+    #[allow(unused)]
+    position: IVec2,
+    vertex_indices: [usize; 4],
+}
+
+impl Polys {
+    pub fn new(position: IVec2, vertex_indices: [usize; 4]) -> Self {
+        Polys {
+            position,
+            vertex_indices,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct TempNavmesh {
+    // pub vertices: IndexMap<IVec2, Connections>,
+    // pub polygons: Vec<[usize; 4]>,
+    pub debug_pa_navmesh: PAMesh,
+    pub navmesh: PathMesh,
+    pub dimensions: Vec2,
+    /// Different for every navmesh built, so that results computed on a previous navmesh of the
+    /// same entity can be told apart
+    pub version: u64,
+    /// Connected component of each polygon of `debug_pa_navmesh`
+    pub polygon_components: Vec<usize>,
+    pub polygon_grid: PolygonGrid,
+    /// Edges of each polygon on the border of the navmesh
+    pub(crate) polygon_boundaries: Vec<Vec<(Vec2, Vec2)>>,
+    /// See [`cumulative_areas`]
+    pub(crate) cumulative_areas: Vec<f32>,
+}
+
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// What's needed from a tilemap to build its navmesh, so that it can be built outside of systems
+pub struct NavmeshInput {
+    /// Center of each tile and if it's walkable
+    tiles: Vec<(Vec2, bool)>,
+    tile_size: Vec2,
+    dimensions: Vec2,
+}
+
+impl NavmeshInput {
+    pub fn from_tilemap(
+        map_type: &TilemapType,
+        grid_size: &TilemapGridSize,
+        tilemap_storage: &TileStorage,
+        transform: &Transform,
+        tile_query: &Query<(&TilePos, &TileCost)>,
+    ) -> Self {
+        let tiles = tilemap_storage
+            .iter()
+            .flatten()
+            .map(|tile_entity| {
+                let (tile_pos, tile_cost) = tile_query.get(*tile_entity).unwrap();
+                let world_pos = tile_pos.center_in_world(grid_size, map_type)
+                    + transform.translation.truncate();
+                (world_pos, tile_cost.0 >= 1)
+            })
+            .collect();
+
+        let width = tilemap_storage.size.x as f32 * grid_size.x;
+        let height = tilemap_storage.size.y as f32 * grid_size.y;
+
+        NavmeshInput {
+            tiles,
+            tile_size: Vec2::new(grid_size.x, grid_size.y),
+            dimensions: Vec2::new(width, height),
+        }
+    }
+
+    pub fn build(self, progress: impl Fn(f32)) -> TempNavmesh {
+        let tile_count = self.tiles.len();
+        let navmesh =
+            build_square_navmesh_with_progress(self.tiles, self.tile_size, tile_count, progress);
+
+        // TODO: Sort the polygons
+        // let temp_polys: Vec<[usize; 4]> = polygons.iter().map(|poly| poly.vertex_indices).collect();
+
+        TempNavmesh::from_mesh(navmesh, self.dimensions)
+    }
+}
+
+impl TempNavmesh {
+    /// A navmesh with a new version, and the lookup structures of `navmesh`
+    pub(crate) fn from_mesh(navmesh: PAMesh, dimensions: Vec2) -> Self {
+        TempNavmesh {
+            // vertices,
+            // polygons: temp_polys,
+            polygon_components: polygon_components(&navmesh),
+            polygon_grid: PolygonGrid::new(&navmesh),
+            polygon_boundaries: boundary_edges(&navmesh),
+            cumulative_areas: cumulative_areas(&navmesh),
+            debug_pa_navmesh: navmesh.clone(),
+            navmesh: PathMesh::from_polyanya_mesh(navmesh),
+            dimensions,
+            version: NEXT_VERSION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Generates the navmesh of a single tilemap from the cost of its tiles.
+pub fn navmesh_from_tilemap(
+    map_type: &TilemapType,
+    grid_size: &TilemapGridSize,
+    tilemap_storage: &TileStorage,
+    transform: &Transform,
+    tile_query: &Query<(&TilePos, &TileCost)>,
+) -> TempNavmesh {
+    NavmeshInput::from_tilemap(map_type, grid_size, tilemap_storage, transform, tile_query)
+        .build(|_| {})
+}
+
+/// Builds and bakes a polyanya mesh with one square polygon per walkable tile.
+///
+/// `tiles` yields the center of each tile and whether it is walkable, `tile_size` is the size of
+/// a tile in the same units, and `tile_count` is only used to size the allocations.
+pub fn build_square_navmesh(
+    tiles: impl IntoIterator<Item = (Vec2, bool)>,
+    tile_size: Vec2,
+    tile_count: usize,
+) -> PAMesh {
+    build_square_navmesh_with_progress(tiles, tile_size, tile_count, |_| {})
+}
+
+/// Same as [`build_square_navmesh`], calling `progress` with the fraction of the work done.
+pub fn build_square_navmesh_with_progress(
+    tiles: impl IntoIterator<Item = (Vec2, bool)>,
+    tile_size: Vec2,
+    tile_count: usize,
+    progress: impl Fn(f32),
+) -> PAMesh {
+    // We have the vertices and their connected polygons, but not if they're an edge
+
+    // num tiles * 1.3 sounds about right?
+    let mut vertices: IndexMap<IVec2, Connections> =
+        IndexMap::with_capacity((tile_count as f32 * 1.3) as usize);
+
+    let mut poly_indices: HashMap<IVec2, isize> = HashMap::new();
+    let mut highest = 0;
+
+    let mut polygons: Vec<Polys> = Vec::with_capacity(tile_count);
+
+    for (tile_idx, (world_pos, walkable)) in tiles.into_iter().enumerate() {
+        // Baking is reported as the last 20%
+        if tile_idx % 1024 == 0 {
+            progress(0.8 * tile_idx as f32 / tile_count.max(1) as f32);
+        }
+        // println!("world_pos: {}", world_pos);
+        let poly_idx = if !walkable {
+            -1
+        } else {
+            let idx = poly_indices.entry(world_pos.as_ivec2()).or_insert(highest);
+            highest += 1;
+            *idx
+        };
+        let mut vertex_indices: [usize; 4] = [0; 4];
+
+        [
+            ((-tile_size.x / 2.0) as i32, (-tile_size.y / 2.0) as i32),
+            ((tile_size.x / 2.0) as i32, (-tile_size.y / 2.0) as i32),
+            ((tile_size.x / 2.0) as i32, (tile_size.y / 2.0) as i32),
+            ((-tile_size.x / 2.0) as i32, (tile_size.y / 2.0) as i32),
+        ]
+        .iter()
+        .enumerate()
+        .for_each(|(idx, &corner_pos)| {
+            let pos = IVec2::new(
+                corner_pos.0 + (world_pos.x) as i32,
+                corner_pos.1 + (world_pos.y) as i32,
+            );
+            let connections_entry = vertices.entry(pos);
+            vertex_indices[idx] = connections_entry.index();
+            let connections = connections_entry.or_insert(Connections::new());
+            if poly_idx > -1 {
+                connections.connection_indices.push(poly_idx);
+            }
+        });
+        if !walkable {
+            continue;
+        } else {
+            polygons.push(Polys::new(world_pos.as_ivec2(), vertex_indices));
+        }
+    }
+
+    let mut pa_vertices: Vec<PAVertex> = Vec::with_capacity(vertices.len());
+    let mut pa_polys: Vec<PAPoly> = Vec::with_capacity(polygons.len());
+
+    // TODO: Sort vertex neighbours, and also add -1 for empty polys
+    // TODO: Do this properly, currently just adding None to ever vertex with <3 connections
+    for (vertex_pos, connections) in vertices.iter_mut() {
+        if connections.connection_indices.len() > 0 {
+            // if all vertex connections are -1, then skip this vertex
+            let mut temp = connections.connection_indices.clone();
+            temp.sort_unstable();
+            if temp[temp.len() - 1] == -1 {
+                // orphan vertex, do nothing
+                continue;
+            }
+        } else {
+            // No connections
+            continue;
+        }
+        if connections.connection_indices.len() < 4 {
+            let mut temp: Vec<isize> = connections.connection_indices.clone();
+            // TODO: This is probably in the wrong place.
+            temp.push(-1);
+
+            let vertex = PAVertex::new(vertex_pos.as_vec2(), temp);
+            pa_vertices.push(vertex);
+        } else {
+            let vertex =
+                PAVertex::new(vertex_pos.as_vec2(), connections.connection_indices.clone());
+            pa_vertices.push(vertex);
+        }
+    }
+
+    for poly in polygons.iter() {
+        let mut neighbours = HashSet::new();
+        for vertex_idx in poly.vertex_indices {
+            let connections = &vertices[vertex_idx];
+            for con in connections.connection_indices.iter() {
+                neighbours.insert(*con);
+            }
+        }
+        let is_one_way: bool = neighbours.len() <= 2;
+        let temp_vertices = poly
+            .vertex_indices
+            .iter()
+            .map(|v_idx| *v_idx as u32)
+            .collect();
+        let polygon = PAPoly::new(temp_vertices, is_one_way);
+        pa_polys.push(polygon);
+    }
+
+    pa_vertices.shrink_to_fit();
+    pa_polys.shrink_to_fit();
+    debug!("Vertices len: {}", pa_vertices.len());
+    debug!("polys len: {}", pa_polys.len());
+
+    let mut navmesh = PAMesh::new(pa_vertices, pa_polys);
+    progress(0.8);
+    let pre_bake = Instant::now();
+    navmesh.bake();
+    let post_bake = Instant::now();
+    info!("time to bake navmesh: {:?}", post_bake - pre_bake);
+    progress(1.0);
+
+    navmesh
+}
@@ -0,0 +1,533 @@
+//! Spatial queries on a [`TempNavmesh`], for points that may be outside of it.
+
+use bevy::{
+    prelude::{IVec2, Vec2},
+    utils::{HashMap, HashSet},
+};
+use polyanya::Mesh as PAMesh;
+
+use super::TempNavmesh;
+
+/// What to do with the start or the target of a path when it isn't on the navmesh.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OffMeshPolicy {
+    /// Move it to the closest point of the navmesh within `NavigationSettings::snap_radius`
+    Snap,
+    /// Fail the request
+    Reject,
+    /// Like `Snap` for the start. The target is moved to the closest point reachable from the
+    /// start, however far it is, even when it's on another part of the navmesh.
+    NearestReachable,
+}
+
+/// Labels the polygons with the index of their connected component. Polygons are connected when
+/// they share an edge.
+pub(crate) fn polygon_components(mesh: &PAMesh) -> Vec<usize> {
+    let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::default();
+    for (polygon_idx, polygon) in mesh.polygons.iter().enumerate() {
+        let count = polygon.vertices.len();
+        for i in 0..count {
+            let a = polygon.vertices[i] as usize;
+            let b = polygon.vertices[(i + 1) % count] as usize;
+            edges
+                .entry((a.min(b), a.max(b)))
+                .or_default()
+                .push(polygon_idx);
+        }
+    }
+    let mut neighbours = vec![Vec::new(); mesh.polygons.len()];
+    for polygons in edges.values() {
+        for a in polygons {
+            for b in polygons {
+                if a != b {
+                    neighbours[*a].push(*b);
+                }
+            }
+        }
+    }
+
+    let mut components = vec![usize::MAX; mesh.polygons.len()];
+    let mut component = 0;
+    for start in 0..mesh.polygons.len() {
+        if components[start] != usize::MAX {
+            continue;
+        }
+        components[start] = component;
+        let mut stack = vec![start];
+        while let Some(polygon) = stack.pop() {
+            for neighbour in &neighbours[polygon] {
+                if components[*neighbour] == usize::MAX {
+                    components[*neighbour] = component;
+                    stack.push(*neighbour);
+                }
+            }
+        }
+        component += 1;
+    }
+    components
+}
+
+/// Edges of each polygon that aren't shared with another polygon, the walls of the navmesh.
+pub(crate) fn boundary_edges(mesh: &PAMesh) -> Vec<Vec<(Vec2, Vec2)>> {
+    let mut edge_count: HashMap<(usize, usize), usize> = HashMap::default();
+    let polygon_edges = |polygon: &polyanya::Polygon| {
+        let count = polygon.vertices.len();
+        (0..count)
+            .map(|i| {
+                (
+                    polygon.vertices[i] as usize,
+                    polygon.vertices[(i + 1) % count] as usize,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    for polygon in &mesh.polygons {
+        for (a, b) in polygon_edges(polygon) {
+            *edge_count.entry((a.min(b), a.max(b))).or_default() += 1;
+        }
+    }
+    mesh.polygons
+        .iter()
+        .map(|polygon| {
+            polygon_edges(polygon)
+                .into_iter()
+                .filter(|(a, b)| edge_count[&((*a).min(*b), (*a).max(*b))] == 1)
+                .map(|(a, b)| (mesh.vertices[a].coords, mesh.vertices[b].coords))
+                .collect()
+        })
+        .collect()
+}
+
+/// Spacing of the points checked on a segment to know if it stays on the navmesh
+const SEGMENT_STEP: f32 = 4.0;
+
+/// Size of the cells of [`PolygonGrid`]
+const GRID_CELL_SIZE: f32 = 32.0;
+
+/// Polygons overlapping each cell of a grid, to find the polygons near a point without going
+/// through all of them.
+#[derive(Default)]
+pub struct PolygonGrid {
+    cells: HashMap<IVec2, Vec<usize>>,
+}
+
+impl PolygonGrid {
+    fn cell(point: Vec2) -> IVec2 {
+        (point / GRID_CELL_SIZE).floor().as_ivec2()
+    }
+
+    pub(crate) fn new(mesh: &PAMesh) -> Self {
+        let mut cells: HashMap<IVec2, Vec<usize>> = HashMap::default();
+        for (polygon_idx, polygon) in mesh.polygons.iter().enumerate() {
+            let (min, max) = polygon.vertices.iter().fold(
+                (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+                |(min, max), vertex| {
+                    let coords = mesh.vertices[*vertex as usize].coords;
+                    (min.min(coords), max.max(coords))
+                },
+            );
+            let (min, max) = (Self::cell(min), Self::cell(max));
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    cells.entry(IVec2::new(x, y)).or_default().push(polygon_idx);
+                }
+            }
+        }
+        PolygonGrid { cells }
+    }
+
+    /// Polygons that may be within `radius` of `point`
+    pub(crate) fn near(&self, point: Vec2, radius: f32) -> HashSet<usize> {
+        let (min, max) = (
+            Self::cell(point - Vec2::splat(radius)),
+            Self::cell(point + Vec2::splat(radius)),
+        );
+        let mut polygons = HashSet::default();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                if let Some(cell) = self.cells.get(&IVec2::new(x, y)) {
+                    polygons.extend(cell.iter().copied());
+                }
+            }
+        }
+        polygons
+    }
+}
+
+/// Area of each polygon added to the areas of the previous ones, to pick polygons weighted by
+/// their area.
+pub(crate) fn cumulative_areas(mesh: &PAMesh) -> Vec<f32> {
+    let mut total = 0.0;
+    mesh.polygons
+        .iter()
+        .map(|polygon| {
+            let vertices: Vec<Vec2> = polygon
+                .vertices
+                .iter()
+                .map(|vertex| mesh.vertices[*vertex as usize].coords)
+                .collect();
+            total += polygon_area(&vertices);
+            total
+        })
+        .collect()
+}
+
+fn triangle_area(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    (b - a).perp_dot(c - a).abs() / 2.0
+}
+
+fn polygon_area(vertices: &[Vec2]) -> f32 {
+    (1..vertices.len().saturating_sub(1))
+        .map(|i| triangle_area(vertices[0], vertices[i], vertices[i + 1]))
+        .sum()
+}
+
+/// Uniformly random point in the convex polygon `vertices`
+fn random_point_in_polygon(rng: &fastrand::Rng, vertices: &[Vec2]) -> Vec2 {
+    // Pick a triangle of the fan weighted by its area, then a point in it
+    let areas: Vec<f32> = (1..vertices.len().saturating_sub(1))
+        .map(|i| triangle_area(vertices[0], vertices[i], vertices[i + 1]))
+        .collect();
+    let mut pick = rng.f32() * areas.iter().sum::<f32>();
+    let triangle = areas
+        .iter()
+        .position(|area| {
+            pick -= area;
+            pick <= 0.0
+        })
+        .unwrap_or(areas.len().saturating_sub(1));
+    let (a, b, c) = (
+        vertices[0],
+        vertices[triangle + 1],
+        vertices[(triangle + 2).min(vertices.len() - 1)],
+    );
+    let (r1, r2) = (rng.f32().sqrt(), rng.f32());
+    a * (1.0 - r1) + b * (r1 * (1.0 - r2)) + c * (r1 * r2)
+}
+
+/// Closest point of the convex polygon `vertices` to `point`, `point` itself if it's inside
+fn closest_point_on_polygon(point: Vec2, vertices: &[Vec2]) -> Vec2 {
+    let edges = || (0..vertices.len()).map(|i| (vertices[i], vertices[(i + 1) % vertices.len()]));
+    let sides: Vec<f32> = edges().map(|(a, b)| (b - a).perp_dot(point - a)).collect();
+    if sides.iter().all(|side| *side >= 0.0) || sides.iter().all(|side| *side <= 0.0) {
+        return point;
+    }
+    edges()
+        .map(|(a, b)| {
+            let edge = b - a;
+            let t =
+                ((point - a).dot(edge) / edge.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+            a + edge * t
+        })
+        .min_by(|a, b| {
+            a.distance_squared(point)
+                .total_cmp(&b.distance_squared(point))
+        })
+        .unwrap_or(point)
+}
+
+impl TempNavmesh {
+    fn polygon_vertices(&self, polygon: usize) -> Vec<Vec2> {
+        let mesh = &self.debug_pa_navmesh;
+        mesh.polygons[polygon]
+            .vertices
+            .iter()
+            .map(|vertex| mesh.vertices[*vertex as usize].coords)
+            .collect()
+    }
+
+    /// Index of the polygon containing `point`
+    pub fn polygon_at(&self, point: Vec2) -> Option<usize> {
+        // Points snapped on an edge may be slightly outside of their polygon
+        self.polygon_grid
+            .near(point, 0.0)
+            .into_iter()
+            .find(|polygon| {
+                let nearest = closest_point_on_polygon(point, &self.polygon_vertices(*polygon));
+                nearest.distance_squared(point) <= 1e-6
+            })
+    }
+
+    /// Connected component of a polygon, paths only exist between points of the same component.
+    pub fn component(&self, polygon: usize) -> usize {
+        self.polygon_components[polygon]
+    }
+
+    /// Closest point of the navmesh to `point`, no further than `max_distance`
+    pub fn nearest_point(&self, point: Vec2, max_distance: f32) -> Option<Vec2> {
+        self.nearest_point_where(point, max_distance, |_| true)
+            .map(|(_, nearest)| nearest)
+    }
+
+    /// Closest point to `point` on the polygons for which `filter` is true, no further than
+    /// `max_distance`, with the index of its polygon.
+    pub fn nearest_point_where(
+        &self,
+        point: Vec2,
+        max_distance: f32,
+        filter: impl Fn(usize) -> bool,
+    ) -> Option<(usize, Vec2)> {
+        let polygons: Box<dyn Iterator<Item = usize>> = if max_distance.is_finite() {
+            Box::new(self.polygon_grid.near(point, max_distance).into_iter())
+        } else {
+            Box::new(0..self.debug_pa_navmesh.polygons.len())
+        };
+        polygons
+            .filter(|polygon| filter(*polygon))
+            .map(|polygon| {
+                let nearest = closest_point_on_polygon(point, &self.polygon_vertices(polygon));
+                (polygon, nearest)
+            })
+            .filter(|(_, nearest)| nearest.distance(point) <= max_distance)
+            .min_by(|(_, a), (_, b)| {
+                a.distance_squared(point)
+                    .total_cmp(&b.distance_squared(point))
+            })
+    }
+
+    /// Random point of the navmesh, polygons are picked weighted by their area. The point can be
+    /// restricted to be within `radius` of a position and on a connected component.
+    pub fn random_point(
+        &self,
+        rng: &fastrand::Rng,
+        around: Option<(Vec2, f32)>,
+        component: Option<usize>,
+    ) -> Option<Vec2> {
+        let is_valid = |polygon: usize, point: Vec2| {
+            component.map_or(true, |component| self.component(polygon) == component)
+                && around.map_or(true, |(center, radius)| center.distance(point) <= radius)
+        };
+
+        // Sampling the whole navmesh is cheap, it's enough unless the restrictions are tight
+        let total_area = self.cumulative_areas.last().copied().unwrap_or_default();
+        if total_area <= 0.0 {
+            return None;
+        }
+        for _ in 0..16 {
+            let pick = rng.f32() * total_area;
+            let polygon = self
+                .cumulative_areas
+                .partition_point(|area| *area < pick)
+                .min(self.cumulative_areas.len() - 1);
+            let point = random_point_in_polygon(rng, &self.polygon_vertices(polygon));
+            if is_valid(polygon, point) {
+                return Some(point);
+            }
+        }
+
+        // Otherwise pick among the polygons matching the restrictions
+        let polygons: Vec<usize> = match around {
+            Some((center, radius)) => self.polygon_grid.near(center, radius).into_iter().collect(),
+            None => (0..self.debug_pa_navmesh.polygons.len()).collect(),
+        };
+        let candidates: Vec<(usize, f32)> = polygons
+            .into_iter()
+            .filter(|polygon| {
+                component.map_or(true, |component| self.component(*polygon) == component)
+            })
+            .filter(|polygon| {
+                around.map_or(true, |(center, radius)| {
+                    let vertices = self.polygon_vertices(*polygon);
+                    closest_point_on_polygon(center, &vertices).distance(center) <= radius
+                })
+            })
+            .map(|polygon| (polygon, polygon_area(&self.polygon_vertices(polygon))))
+            .collect();
+        let mut pick = rng.f32() * candidates.iter().map(|(_, area)| area).sum::<f32>();
+        let (polygon, _) = *candidates
+            .iter()
+            .find(|(_, area)| {
+                pick -= area;
+                pick <= 0.0
+            })
+            .or_else(|| candidates.last())?;
+        let vertices = self.polygon_vertices(polygon);
+        (0..16)
+            .map(|_| random_point_in_polygon(rng, &vertices))
+            .find(|point| is_valid(polygon, *point))
+            // The polygon is only partly within the radius
+            .or_else(|| around.map(|(center, _)| closest_point_on_polygon(center, &vertices)))
+    }
+
+    /// Moves the start and the target of a path on the navmesh according to `policy`, `None` if
+    /// the path can't be computed.
+    pub fn resolve_path_ends(
+        &self,
+        from: Vec2,
+        to: Vec2,
+        policy: OffMeshPolicy,
+        snap_radius: f32,
+    ) -> Option<(Vec2, Vec2)> {
+        let from = if self.navmesh.is_in_mesh(from) {
+            from
+        } else if policy == OffMeshPolicy::Reject {
+            return None;
+        } else {
+            self.nearest_point(from, snap_radius)?
+        };
+
+        let to = match policy {
+            OffMeshPolicy::Reject if !self.navmesh.is_in_mesh(to) => return None,
+            OffMeshPolicy::Snap if !self.navmesh.is_in_mesh(to) => {
+                self.nearest_point(to, snap_radius)?
+            }
+            OffMeshPolicy::NearestReachable => {
+                let component = self.component(self.polygon_at(from)?);
+                match self.polygon_at(to) {
+                    Some(polygon) if self.component(polygon) == component => to,
+                    _ => {
+                        self.nearest_point_where(to, f32::INFINITY, |polygon| {
+                            self.component(polygon) == component
+                        })?
+                        .1
+                    }
+                }
+            }
+            _ => to,
+        };
+        Some((from, to))
+    }
+
+    /// If the straight line between `from` and `to` stays on the navmesh
+    pub fn is_segment_in_mesh(&self, from: Vec2, to: Vec2) -> bool {
+        let steps = (from.distance(to) / SEGMENT_STEP).ceil().max(1.0) as usize;
+        (0..=steps).all(|step| {
+            self.navmesh
+                .is_in_mesh(from.lerp(to, step as f32 / steps as f32))
+        })
+    }
+
+    /// Polygons crossed by the line going through `points`, touching a polygon counts
+    pub fn polygons_along(&self, points: &[Vec2]) -> Vec<usize> {
+        let mut polygons: Vec<usize> = points
+            .first()
+            .and_then(|point| self.polygon_at(*point))
+            .into_iter()
+            .collect();
+        for segment in points.windows(2) {
+            // Pieces of the size of a grid cell, so that few polygons are tested for each
+            let pieces = (segment[0].distance(segment[1]) / GRID_CELL_SIZE)
+                .ceil()
+                .max(1.0) as usize;
+            for piece in 0..pieces {
+                let from = segment[0].lerp(segment[1], piece as f32 / pieces as f32);
+                let to = segment[0].lerp(segment[1], (piece + 1) as f32 / pieces as f32);
+                polygons.extend(
+                    self.polygon_grid
+                        .near((from + to) / 2.0, from.distance(to) / 2.0)
+                        .into_iter()
+                        .filter(|polygon| {
+                            segment_touches_polygon(from, to, &self.polygon_vertices(*polygon))
+                        }),
+                );
+            }
+        }
+        polygons.sort_unstable();
+        polygons.dedup();
+        polygons
+    }
+}
+
+/// If the segment from `from` to `to` touches the convex polygon `vertices`, by the separating
+/// axis theorem: they don't touch only if their projections on the normal of an edge of the
+/// polygon, or on the normal of the segment, don't overlap.
+fn segment_touches_polygon(from: Vec2, to: Vec2, vertices: &[Vec2]) -> bool {
+    let edges = (0..vertices.len()).map(|i| vertices[(i + 1) % vertices.len()] - vertices[i]);
+    edges.chain(std::iter::once(to - from)).all(|edge| {
+        let axis = edge.perp();
+        let (min, max) = vertices.iter().map(|vertex| vertex.dot(axis)).fold(
+            (f32::INFINITY, f32::NEG_INFINITY),
+            |(min, max), projection| (min.min(projection), max.max(projection)),
+        );
+        let (from, to) = (from.dot(axis), to.dot(axis));
+        let tolerance = 1e-4 * axis.length();
+        from.max(to) >= min - tolerance && from.min(to) <= max + tolerance
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::navigation::build_square_navmesh;
+
+    const SQUARE: [Vec2; 4] = [
+        Vec2::new(0.0, 0.0),
+        Vec2::new(2.0, 0.0),
+        Vec2::new(2.0, 2.0),
+        Vec2::new(0.0, 2.0),
+    ];
+
+    /// A navmesh of `width` by `height` walkable tiles of 2 units, starting at the origin
+    fn open_navmesh(width: u32, height: u32) -> TempNavmesh {
+        let tiles = (0..height).flat_map(|y| {
+            (0..width).map(move |x| (Vec2::new(x as f32, y as f32) * 2.0 + 1.0, true))
+        });
+        let mesh = build_square_navmesh(tiles, Vec2::splat(2.0), (width * height) as usize);
+        TempNavmesh::from_mesh(mesh, Vec2::new(width as f32, height as f32) * 2.0)
+    }
+
+    #[test]
+    fn segment_inside_touches_polygon() {
+        assert!(segment_touches_polygon(
+            Vec2::new(0.5, 0.5),
+            Vec2::new(1.5, 1.0),
+            &SQUARE
+        ));
+        // Through the polygon, both ends outside
+        assert!(segment_touches_polygon(
+            Vec2::new(-1.0, 1.0),
+            Vec2::new(3.0, 1.0),
+            &SQUARE
+        ));
+    }
+
+    #[test]
+    fn segment_clipping_a_corner_touches_polygon() {
+        assert!(segment_touches_polygon(
+            Vec2::new(1.4, -0.5),
+            Vec2::new(2.4, 0.5),
+            &SQUARE
+        ));
+        // Through the corner itself
+        assert!(segment_touches_polygon(
+            Vec2::new(1.0, -1.0),
+            Vec2::new(3.0, 1.0),
+            &SQUARE
+        ));
+    }
+
+    #[test]
+    fn segment_beside_polygon_doesnt_touch_it() {
+        // Passes the corner diagonally, outside of the bounding box test of the edges
+        assert!(!segment_touches_polygon(
+            Vec2::new(1.6, -0.5),
+            Vec2::new(2.6, 0.5),
+            &SQUARE
+        ));
+        assert!(!segment_touches_polygon(
+            Vec2::new(3.0, -1.0),
+            Vec2::new(3.0, 3.0),
+            &SQUARE
+        ));
+    }
+
+    #[test]
+    fn polygons_along_include_clipped_corners() {
+        let temp = open_navmesh(2, 2);
+        let polygon = |x: f32, y: f32| temp.polygon_at(Vec2::new(x, y)).unwrap();
+        // Goes from the bottom left tile to the top right one, clipping the bottom right tile
+        // for less than a unit just below their shared corner
+        let along = temp.polygons_along(&[Vec2::new(1.0, 0.9), Vec2::new(3.0, 2.9)]);
+
+        let mut expected = vec![polygon(1.0, 1.0), polygon(3.0, 1.0), polygon(3.0, 3.0)];
+        expected.sort_unstable();
+        assert_eq!(along, expected);
+    }
+
+    #[test]
+    fn polygons_along_long_segments() {
+        let temp = open_navmesh(40, 1);
+        let along = temp.polygons_along(&[Vec2::new(1.0, 1.0), Vec2::new(79.0, 1.0)]);
+        assert_eq!(along.len(), 40);
+    }
+}
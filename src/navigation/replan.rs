@@ -0,0 +1,158 @@
+//! Replanning of the paths going through the parts of a navmesh that changed.
+//!
+//! Each [`Path`] records the version of the navmesh it was computed on and the polygons it goes
+//! through. When a [`TempNavmesh`] is replaced, its polygons are compared with the previous ones
+//! and only the agents whose path crosses a polygon that disappeared or changed search again.
+
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{is_on_screen, FindingPath, NavmeshId, Path, PathPriority, Target, TempNavmesh};
+
+impl Path {
+    /// A path from `from` through `path` on `temp`, recording the polygons it goes through.
+    pub fn on_navmesh(path: Vec<Vec2>, from: Vec2, temp: &TempNavmesh) -> Self {
+        let mut points = Vec::with_capacity(path.len() + 1);
+        points.push(from);
+        points.extend_from_slice(&path);
+        Path {
+            polygons: temp.polygons_along(&points),
+            navmesh_version: temp.version,
+            path,
+        }
+    }
+}
+
+/// Polygons of a navmesh version, identified by the exact coordinates of their vertices
+struct NavmeshSnapshot {
+    version: u64,
+    polygons: HashMap<Vec<[u32; 2]>, usize>,
+}
+
+impl NavmeshSnapshot {
+    fn new(temp: &TempNavmesh) -> Self {
+        let mesh = &temp.debug_pa_navmesh;
+        let polygons = mesh
+            .polygons
+            .iter()
+            .enumerate()
+            .map(|(index, polygon)| {
+                let shape = polygon
+                    .vertices
+                    .iter()
+                    .map(|vertex| {
+                        let coords = mesh.vertices[*vertex as usize].coords;
+                        [coords.x.to_bits(), coords.y.to_bits()]
+                    })
+                    .collect();
+                (shape, index)
+            })
+            .collect();
+        NavmeshSnapshot {
+            version: temp.version,
+            polygons,
+        }
+    }
+
+    /// Index in `self` of each polygon of `previous`, `None` for the ones that changed
+    fn remap(&self, previous: &NavmeshSnapshot) -> HashMap<usize, Option<usize>> {
+        previous
+            .polygons
+            .iter()
+            .map(|(shape, index)| (*index, self.polygons.get(shape).copied()))
+            .collect()
+    }
+}
+
+/// Last version seen of each navmesh
+#[derive(Default)]
+pub(crate) struct NavmeshSnapshots(HashMap<Entity, NavmeshSnapshot>);
+
+/// Computes again the paths crossing polygons that changed, and the paths being computed on a
+/// navmesh that changed. Other paths are updated to the new version.
+pub(crate) fn replan_paths(
+    mut commands: Commands,
+    changed_mesh_q: Query<(Entity, &TempNavmesh), Changed<TempNavmesh>>,
+    removed_meshes: RemovedComponents<TempNavmesh>,
+    mut paths: Query<(Entity, &mut Path, &Target, &Transform, &NavmeshId)>,
+    finding: Query<(Entity, &Target, &Transform, &NavmeshId), With<FindingPath>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    mut snapshots: Local<NavmeshSnapshots>,
+) {
+    for removed in removed_meshes.iter() {
+        snapshots.0.remove(&removed);
+    }
+
+    // Player orders keep their priority, the agents the player sees are replanned first
+    let replan = |commands: &mut Commands, entity: Entity, target: &Target, position: Vec2| {
+        let visibility = PathPriority::from_visibility(is_on_screen(cameras.iter(), position));
+        commands
+            .entity(entity)
+            .remove::<Path>()
+            .remove::<FindingPath>()
+            .insert(Target {
+                target: target.target,
+                priority: target.priority.max(visibility),
+            });
+    };
+
+    for (mesh_entity, temp) in changed_mesh_q.iter() {
+        let snapshot = NavmeshSnapshot::new(temp);
+        // Without the previous version every path on this navmesh is suspicious
+        let remap = snapshots
+            .0
+            .get(&mesh_entity)
+            .filter(|previous| previous.version != temp.version)
+            .map(|previous| (previous.version, snapshot.remap(previous)));
+
+        let mut replanned = 0;
+        finding.for_each(|(entity, target, transform, navmesh_id)| {
+            if navmesh_id.0 == mesh_entity {
+                replan(
+                    &mut commands,
+                    entity,
+                    target,
+                    transform.translation.truncate(),
+                );
+                replanned += 1;
+            }
+        });
+        paths.for_each_mut(|(entity, mut path, target, transform, navmesh_id)| {
+            if navmesh_id.0 != mesh_entity || path.navmesh_version == temp.version {
+                return;
+            }
+            let polygons = remap.as_ref().and_then(|(previous_version, remap)| {
+                if path.navmesh_version != *previous_version {
+                    return None;
+                }
+                path.polygons
+                    .iter()
+                    .map(|polygon| remap.get(polygon).copied().flatten())
+                    .collect::<Option<Vec<usize>>>()
+            });
+            match polygons {
+                Some(mut polygons) => {
+                    polygons.sort_unstable();
+                    path.polygons = polygons;
+                    path.navmesh_version = temp.version;
+                }
+                None => {
+                    replan(
+                        &mut commands,
+                        entity,
+                        target,
+                        transform.translation.truncate(),
+                    );
+                    replanned += 1;
+                }
+            }
+        });
+        if replanned > 0 {
+            info!(
+                "navmesh {:?} changed, replanning {} paths",
+                mesh_entity, replanned
+            );
+        }
+
+        snapshots.0.insert(mesh_entity, snapshot);
+    }
+}
@@ -0,0 +1,571 @@
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::{BinaryHeap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use async_channel::{Receiver, Sender};
+use bevy::{
+    ecs::entity::Entities,
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::{HashMap, HashSet, Instant},
+};
+use bevy_pathmesh::PathMesh;
+
+use super::{
+    cache::{PathCache, PathKey},
+    NavigationSettings, TaskMode, TempNavmesh,
+};
+
+/// Identifies a [`PathRequest`] and its [`PathResponse`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PathRequestId(u64);
+
+/// Hands out unique [`PathRequestId`]s.
+#[derive(Default)]
+pub struct PathRequestIds(u64);
+
+impl PathRequestIds {
+    pub fn next_id(&mut self) -> PathRequestId {
+        self.0 += 1;
+        PathRequestId(self.0)
+    }
+}
+
+/// Order in which queued requests are started, from the last to the first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PathPriority {
+    /// Agents wandering around off screen with nothing to do
+    Idle,
+    /// Agents the player can't see
+    OffScreen,
+    /// Agents the player can see
+    OnScreen,
+    /// Orders given by the player
+    Player,
+}
+
+impl Default for PathPriority {
+    fn default() -> Self {
+        PathPriority::OnScreen
+    }
+}
+
+impl PathPriority {
+    /// Priority of an agent that's `on_screen` or not, see [`is_on_screen`]
+    pub fn from_visibility(on_screen: bool) -> Self {
+        if on_screen {
+            PathPriority::OnScreen
+        } else {
+            PathPriority::OffScreen
+        }
+    }
+}
+
+/// If `position` is in the viewport of one of `cameras`
+pub fn is_on_screen<'a>(
+    cameras: impl IntoIterator<Item = (&'a Camera, &'a GlobalTransform)>,
+    position: Vec2,
+) -> bool {
+    cameras.into_iter().any(|(camera, transform)| {
+        match (
+            camera.world_to_viewport(transform, position.extend(0.0)),
+            camera.logical_viewport_size(),
+        ) {
+            (Some(viewport), Some(size)) => {
+                viewport.cmpge(Vec2::ZERO).all() && viewport.cmple(size).all()
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Asks for a path from `from` to `to`, answered later with a [`PathResponse`] with the same id.
+///
+/// Requests are queued by priority, and only a few are started each frame, see
+/// [`NavigationSettings`]. Ends outside of the navmesh are handled with its `off_mesh_policy`.
+/// A request supersedes the pending ones from the same requester, they are cancelled and never
+/// answered. Requests from a despawned requester are cancelled too. Requests identical to a
+/// recent one are answered with the same path, see `NavigationSettings::path_cache_size`.
+#[derive(Clone)]
+pub struct PathRequest {
+    pub id: PathRequestId,
+    pub priority: PathPriority,
+    pub from: Vec2,
+    pub to: Vec2,
+    /// The navmesh entity to search, or the first navmesh containing `from`
+    pub navmesh: Option<Entity>,
+    /// Entity the path is for, copied to the response
+    pub requester: Option<Entity>,
+}
+
+pub struct PathResponse {
+    pub id: PathRequestId,
+    pub requester: Option<Entity>,
+    /// Points to go through after `from`, `None` if there is no path
+    pub path: Option<Vec<Vec2>>,
+    /// Length of the path, 0 if there is none
+    pub length: f32,
+    /// Seconds between the start of the task and the start of the search
+    pub delay: f32,
+    /// Seconds spent searching
+    pub duration: f32,
+}
+
+/// Timings of the last 100 path computations, in seconds, and the current load.
+#[derive(Default)]
+pub struct NavigationStats {
+    /// Time spent searching for the path
+    pub pathfinding_duration: VecDeque<f32>,
+    /// Time between the start of the task and the start of the search
+    pub task_delay: VecDeque<f32>,
+    /// Requests waiting to be started
+    pub queued: usize,
+    /// Requests being computed
+    pub in_flight: usize,
+    /// Requests answered from the path cache or by an identical request in flight
+    pub cache_hits: u64,
+    /// Requests that needed their own search
+    pub cache_misses: u64,
+}
+
+impl NavigationStats {
+    /// Fraction of the requests that didn't need their own search
+    pub fn cache_hit_rate(&self) -> f32 {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.cache_hits as f32 / total as f32
+        }
+    }
+}
+
+struct QueuedPath {
+    request: PathRequest,
+    /// Requests with the same priority are started in the order they were sent
+    sequence: u64,
+}
+
+impl PartialEq for QueuedPath {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for QueuedPath {}
+
+impl PartialOrd for QueuedPath {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedPath {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.request
+            .priority
+            .cmp(&other.request.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Requests waiting for a free slot, highest priority first
+#[derive(Default)]
+pub(crate) struct PathQueue {
+    queue: BinaryHeap<QueuedPath>,
+    sequence: u64,
+    /// Latest request of each requester, older ones are dropped when they're dequeued
+    latest: HashMap<Entity, PathRequestId>,
+    /// Requests in `queue` without a requester, they are never superseded
+    without_requester: usize,
+}
+
+impl PathQueue {
+    fn push(&mut self, request: PathRequest) {
+        match request.requester {
+            Some(requester) => {
+                self.latest.insert(requester, request.id);
+            }
+            None => self.without_requester += 1,
+        }
+        self.sequence += 1;
+        self.queue.push(QueuedPath {
+            request,
+            sequence: self.sequence,
+        });
+    }
+
+    /// Next request that hasn't been superseded and whose requester still exists
+    fn pop(&mut self, entities: &Entities) -> Option<PathRequest> {
+        while let Some(QueuedPath { request, .. }) = self.queue.pop() {
+            let requester = match request.requester {
+                Some(requester) => requester,
+                None => {
+                    self.without_requester -= 1;
+                    return Some(request);
+                }
+            };
+            if self.latest.get(&requester) != Some(&request.id) {
+                continue;
+            }
+            self.latest.remove(&requester);
+            if entities.contains(requester) {
+                return Some(request);
+            }
+        }
+        None
+    }
+
+    /// Requests waiting to be started, without the superseded ones still in `queue`
+    fn len(&self) -> usize {
+        self.latest.len() + self.without_requester
+    }
+}
+
+/// Result of a single request, sent back through the channel of [`PendingPaths`]
+struct PathResult {
+    id: PathRequestId,
+    path: Option<polyanya::Path>,
+    delay: f32,
+    duration: f32,
+}
+
+struct PendingPath {
+    requester: Option<Entity>,
+    /// Key of the task computing this path in `PendingPaths::tasks`
+    task: u64,
+    /// Checked by the task before searching
+    cancelled: Arc<AtomicBool>,
+}
+
+/// A task computing one or more paths, cancelled when dropped
+struct PathTask {
+    _task: Task<()>,
+    /// Paths of this task still waiting for their result
+    remaining: usize,
+}
+
+/// Requests being computed on the async compute pool. The results of all tasks are sent through
+/// the same channel, drained once per frame.
+pub(crate) struct PendingPaths {
+    paths: HashMap<PathRequestId, PendingPath>,
+    tasks: HashMap<u64, PathTask>,
+    next_task: u64,
+    sender: Sender<Vec<PathResult>>,
+    receiver: Receiver<Vec<PathResult>>,
+}
+
+impl Default for PendingPaths {
+    fn default() -> Self {
+        let (sender, receiver) = async_channel::unbounded();
+        PendingPaths {
+            paths: HashMap::default(),
+            tasks: HashMap::default(),
+            next_task: 0,
+            sender,
+            receiver,
+        }
+    }
+}
+
+impl PendingPaths {
+    /// Spawns a task solving `requests` one after the other, sending the results together.
+    fn spawn(&mut self, requests: &[PathRequest], mesh: PathMesh, task_mode: TaskMode) {
+        self.next_task += 1;
+        let task_key = self.next_task;
+        let batch: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                let cancelled = Arc::new(AtomicBool::new(false));
+                self.paths.insert(
+                    request.id,
+                    PendingPath {
+                        requester: request.requester,
+                        task: task_key,
+                        cancelled: cancelled.clone(),
+                    },
+                );
+                (request.id, request.from, request.to, cancelled)
+            })
+            .collect();
+
+        let sender = self.sender.clone();
+        let start = Instant::now();
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let delay = (Instant::now() - start).as_secs_f32();
+            let mut results = Vec::with_capacity(batch.len());
+            for (id, from, to, cancelled) in batch {
+                // Leave the worker to the next task, nobody is waiting for this path anymore
+                if cancelled.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let search_start = Instant::now();
+                let path = if task_mode == TaskMode::Async {
+                    mesh.get_path(from, to).await
+                } else {
+                    mesh.path(from, to)
+                };
+                results.push(PathResult {
+                    id,
+                    path,
+                    delay,
+                    duration: (Instant::now() - search_start).as_secs_f32(),
+                });
+            }
+            // The receiver lives as long as the app
+            let _ = sender.send(results).await;
+        });
+        self.tasks.insert(
+            task_key,
+            PathTask {
+                _task: task,
+                remaining: requests.len(),
+            },
+        );
+    }
+
+    /// Forgets a path, dropping its task when it has no other path left
+    fn remove(&mut self, id: PathRequestId) -> Option<PendingPath> {
+        let path = self.paths.remove(&id)?;
+        if let Some(task) = self.tasks.get_mut(&path.task) {
+            task.remaining -= 1;
+            if task.remaining == 0 {
+                self.tasks.remove(&path.task);
+            }
+        }
+        Some(path)
+    }
+
+    /// Cancels the paths matching `predicate`, returning their ids
+    fn cancel(&mut self, predicate: impl Fn(&PendingPath) -> bool) -> Vec<PathRequestId> {
+        let cancelled: Vec<PathRequestId> = self
+            .paths
+            .iter()
+            .filter(|(_, path)| predicate(path))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &cancelled {
+            if let Some(path) = self.remove(*id) {
+                path.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+        cancelled
+    }
+}
+
+/// Cancels the pending paths and the requests waiting for an identical one whose requester
+/// matches `predicate`. Requests waiting for a cancelled one are queued again.
+fn cancel_requests(
+    pending: &mut PendingPaths,
+    cache: &mut PathCache,
+    queue: &mut PathQueue,
+    predicate: impl Fn(Entity) -> bool,
+) {
+    cache.cancel_followers(&predicate);
+    let cancelled =
+        pending.cancel(|path| matches!(path.requester, Some(requester) if predicate(requester)));
+    for id in cancelled {
+        for follower in cache.abandon(id) {
+            queue.push(follower);
+        }
+    }
+}
+
+/// Cancels the requests of `requesters`, queued or being computed, they are never answered.
+pub(crate) fn cancel_requesters(
+    pending: &mut PendingPaths,
+    cache: &mut PathCache,
+    queue: &mut PathQueue,
+    requesters: &HashSet<Entity>,
+) {
+    // Queued requests are dropped when they're dequeued
+    queue
+        .latest
+        .retain(|requester, _| !requesters.contains(requester));
+    cancel_requests(pending, cache, queue, |requester| {
+        requesters.contains(&requester)
+    });
+}
+
+/// Queues the new requests, then starts as many as allowed by [`NavigationSettings`]. Requests
+/// identical to a recent or running one don't use a slot.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn process_path_requests(
+    mut requests: EventReader<PathRequest>,
+    mut responses: EventWriter<PathResponse>,
+    mut queue: ResMut<PathQueue>,
+    mut pending: ResMut<PendingPaths>,
+    mut cache: ResMut<PathCache>,
+    mut stats: ResMut<NavigationStats>,
+    settings: Res<NavigationSettings>,
+    mesh_query: Query<(Entity, &TempNavmesh)>,
+    changed_mesh_q: Query<Entity, Changed<TempNavmesh>>,
+    entities: &Entities,
+) {
+    for changed in changed_mesh_q.iter() {
+        cache.invalidate(changed);
+    }
+
+    // The new requests supersede the pending ones of their requesters, cancelled in one pass
+    let new_requests: Vec<&PathRequest> = requests.iter().collect();
+    let requesters: HashSet<Entity> = new_requests
+        .iter()
+        .filter_map(|request| request.requester)
+        .collect();
+    if !requesters.is_empty() {
+        cancel_requesters(&mut pending, &mut cache, &mut queue, &requesters);
+    }
+    for request in new_requests {
+        queue.push(request.clone());
+    }
+
+    let free_slots = settings
+        .max_paths_in_flight
+        .saturating_sub(pending.paths.len())
+        .min(settings.max_path_starts_per_frame);
+    let mut started = 0;
+    let mut batches: HashMap<Entity, Vec<PathRequest>> = HashMap::default();
+    while started < free_slots {
+        let mut request = match queue.pop(entities) {
+            Some(request) => request,
+            None => break,
+        };
+
+        let navmesh = match request.navmesh {
+            Some(navmesh) => mesh_query.get(navmesh).ok(),
+            None => mesh_query
+                .iter()
+                .find(|(_, temp)| temp.navmesh.is_in_mesh(request.from))
+                .or_else(|| {
+                    // Closest navmesh the start can be snapped on
+                    mesh_query
+                        .iter()
+                        .filter_map(|mesh| {
+                            let nearest =
+                                mesh.1.nearest_point(request.from, settings.snap_radius)?;
+                            Some((mesh, nearest.distance(request.from)))
+                        })
+                        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .map(|(mesh, _)| mesh)
+                }),
+        };
+        let resolved = navmesh.and_then(|(navmesh, temp)| {
+            let (from, to) = temp.resolve_path_ends(
+                request.from,
+                request.to,
+                settings.off_mesh_policy,
+                settings.snap_radius,
+            )?;
+            Some((navmesh, temp, from, to))
+        });
+        if let Some((navmesh, temp, from, to)) = resolved {
+            request.from = from;
+            request.to = to;
+
+            let key = PathKey::new(navmesh, temp, from, to, settings.path_cache_cell);
+            if let Some((path, length)) = cache.get(&key) {
+                stats.cache_hits += 1;
+                responses.send(PathResponse {
+                    id: request.id,
+                    requester: request.requester,
+                    path,
+                    length,
+                    delay: 0.0,
+                    duration: 0.0,
+                });
+                continue;
+            }
+            if cache.join(&key, &request) {
+                stats.cache_hits += 1;
+                continue;
+            }
+            stats.cache_misses += 1;
+            cache.start(key, request.id);
+
+            started += 1;
+            if settings.task_mode == TaskMode::Batched {
+                batches.entry(navmesh).or_default().push(request);
+            } else {
+                pending.spawn(&[request], temp.navmesh.clone(), settings.task_mode);
+            }
+        } else {
+            responses.send(PathResponse {
+                id: request.id,
+                requester: request.requester,
+                path: None,
+                length: 0.0,
+                delay: 0.0,
+                duration: 0.0,
+            });
+        }
+    }
+
+    for (navmesh, requests) in batches {
+        let mesh = &mesh_query.get(navmesh).unwrap().1.navmesh;
+        for batch in requests.chunks(settings.batch_size.max(1)) {
+            pending.spawn(batch, mesh.clone(), TaskMode::Batched);
+        }
+    }
+
+    stats.queued = queue.len();
+    stats.in_flight = pending.paths.len();
+}
+
+pub(crate) fn poll_path_requests(
+    mut pending: ResMut<PendingPaths>,
+    mut cache: ResMut<PathCache>,
+    mut queue: ResMut<PathQueue>,
+    mut responses: EventWriter<PathResponse>,
+    mut stats: ResMut<NavigationStats>,
+    settings: Res<NavigationSettings>,
+    entities: &Entities,
+) {
+    cancel_requests(&mut pending, &mut cache, &mut queue, |requester| {
+        !entities.contains(requester)
+    });
+
+    while let Ok(results) = pending.receiver.try_recv() {
+        for result in results {
+            // Cancelled requests were already removed
+            let request = match pending.remove(result.id) {
+                Some(request) => request,
+                None => continue,
+            };
+            stats.pathfinding_duration.push_front(result.duration);
+            stats.pathfinding_duration.truncate(100);
+            stats.task_delay.push_front(result.delay);
+            stats.task_delay.truncate(100);
+
+            let length = result
+                .path
+                .as_ref()
+                .map(|path| path.length)
+                .unwrap_or_default();
+            let path = result.path.map(|path| path.path);
+            let followers = cache.finish(result.id, &path, length, settings.path_cache_size);
+            for follower in followers {
+                responses.send(PathResponse {
+                    id: follower.id,
+                    requester: follower.requester,
+                    path: path.clone(),
+                    length,
+                    delay: result.delay,
+                    duration: 0.0,
+                });
+            }
+            responses.send(PathResponse {
+                id: result.id,
+                requester: request.requester,
+                path,
+                length,
+                delay: result.delay,
+                duration: result.duration,
+            });
+        }
+    }
+}
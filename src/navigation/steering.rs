@@ -0,0 +1,325 @@
+use bevy::{math::Vec3Swizzles, prelude::*};
+
+use super::{
+    avoidance::{avoiding_velocity, Agent, AgentGrid},
+    flow_field::{FlowField, FlowFields, FlowTarget},
+    NavigationSettings, Navigator, NavmeshId, Path, Target, TempNavmesh,
+};
+
+/// Distance to the end of the path under which the agent has arrived, avoidance keeps agents
+/// further than their radius from walls so it's at least that
+const ARRIVED_DISTANCE: f32 = 0.5;
+/// How much closer to its goal an agent must get to be making progress
+const PROGRESS_DISTANCE: f32 = 1.0;
+/// Rotates `desired` toward `current` so that they are at most `max_angle` radians apart
+fn limit_turn(current: Vec2, desired: Vec2, max_angle: f32) -> Vec2 {
+    if current.length_squared() < f32::EPSILON || desired.length_squared() < f32::EPSILON {
+        return desired;
+    }
+    let angle = current.angle_between(desired);
+    if angle.abs() <= max_angle {
+        return desired;
+    }
+    Vec2::from_angle(max_angle.copysign(angle)).rotate(current.normalize()) * desired.length()
+}
+
+/// Velocity the agent wants to have: full speed toward the next waypoint, slowing down when
+/// arriving at the end of the path.
+fn desired_velocity(navigator: &Navigator, position: Vec2, path: &Path) -> Vec2 {
+    let next = path.path[0];
+    let toward = next - position;
+    let distance = toward.length();
+    if distance < f32::EPSILON {
+        return Vec2::ZERO;
+    }
+    let speed = if path.path.len() == 1 {
+        navigator.speed * (distance / navigator.arrival_radius.max(f32::EPSILON)).min(1.0)
+    } else {
+        navigator.speed
+    };
+    toward / distance * speed
+}
+
+/// Moves by `step` if it stays on the navmesh, otherwise slides along the edge it hits.
+fn constrain_to_mesh(temp: Option<&TempNavmesh>, position: Vec2, step: Vec2) -> Vec2 {
+    let temp = match temp {
+        Some(temp) => temp,
+        None => return step,
+    };
+    // Agents that are already outside can move back in
+    if !temp.navmesh.is_in_mesh(position) {
+        return step;
+    }
+    [step, Vec2::new(step.x, 0.0), Vec2::new(0.0, step.y)]
+        .into_iter()
+        .find(|step| temp.navmesh.is_in_mesh(position + *step))
+        .unwrap_or(Vec2::ZERO)
+}
+
+/// Steers navigators along their path, avoids the other navigators when
+/// [`AvoidanceSettings::enabled`], then moves them without leaving the navmesh.
+pub(crate) fn move_navigator(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &mut Transform,
+        Option<&mut Path>,
+        Option<&FlowTarget>,
+        &mut Navigator,
+        Option<&NavmeshId>,
+    )>,
+    mesh_q: Query<&TempNavmesh>,
+    flow_fields: Res<FlowFields>,
+    settings: Res<NavigationSettings>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+
+    // Velocity each agent would like to have, and the end of its path when it's the next point
+    let mut agents = Vec::new();
+    let mut path_ends = Vec::new();
+    query.for_each_mut(
+        |(entity, transform, path, flow_target, mut navigator, navmesh_id)| {
+            let temp = navmesh_id.and_then(|navmesh_id| mesh_q.get(navmesh_id.0).ok());
+            let position = transform.translation.xy();
+            let (preferred_velocity, path_end) = match (path, flow_target) {
+                (Some(path), _) => steer(
+                    &mut commands,
+                    entity,
+                    position,
+                    path,
+                    &mut navigator,
+                    temp,
+                    &settings,
+                    delta,
+                )
+                .unwrap_or((Vec2::ZERO, None)),
+                (None, Some(flow_target)) => steer_flow(
+                    &mut commands,
+                    entity,
+                    position,
+                    flow_target,
+                    &mut navigator,
+                    temp,
+                    navmesh_id
+                        .and_then(|navmesh_id| flow_fields.get(navmesh_id.0, flow_target.target)),
+                    &settings,
+                    delta,
+                )
+                .unwrap_or((Vec2::ZERO, None)),
+                // Idle agents stop, but still move aside for the others
+                (None, None) => (
+                    navigator.velocity
+                        - navigator
+                            .velocity
+                            .clamp_length_max(navigator.max_acceleration * delta),
+                    None,
+                ),
+            };
+            agents.push(Agent {
+                entity,
+                position,
+                velocity: navigator.velocity,
+                preferred_velocity,
+                radius: navigator.radius,
+                max_speed: navigator.speed,
+                navmesh: navmesh_id.map(|navmesh_id| navmesh_id.0),
+            });
+            path_ends.push(path_end);
+        },
+    );
+
+    let avoidance = &settings.avoidance;
+    let grid = avoidance
+        .enabled
+        .then(|| AgentGrid::new(&agents, avoidance.neighbour_distance));
+    for (index, agent) in agents.iter().enumerate() {
+        let temp = agent.navmesh.and_then(|navmesh| mesh_q.get(navmesh).ok());
+        let velocity = match &grid {
+            Some(grid) => {
+                let neighbours = grid.neighbours(
+                    &agents,
+                    index,
+                    avoidance.neighbour_distance,
+                    avoidance.max_neighbours,
+                );
+                avoiding_velocity(&agents, index, &neighbours, temp, avoidance, delta)
+            }
+            None => agent.preferred_velocity,
+        };
+
+        // Never step past the end of the path, whatever the frame rate
+        let mut step = velocity * delta;
+        if let Some(end) = path_ends[index] {
+            if step.length() > end.distance(agent.position) {
+                step = end - agent.position;
+            }
+        }
+        let step = constrain_to_mesh(temp, agent.position, step);
+        if let Ok((_, mut transform, _, _, mut navigator, _)) = query.get_mut(agent.entity) {
+            navigator.velocity = step / delta;
+            transform.translation += step.extend(0.0);
+        }
+    }
+}
+
+/// Velocity the agent wants to have to follow its path, limited by its acceleration and turn
+/// rate, and the end of the path if it's the next point. Returns `None` and removes the path
+/// when the agent has arrived.
+#[allow(clippy::too_many_arguments)]
+fn steer(
+    commands: &mut Commands,
+    entity: Entity,
+    position: Vec2,
+    mut path: Mut<Path>,
+    navigator: &mut Navigator,
+    temp: Option<&TempNavmesh>,
+    settings: &NavigationSettings,
+    delta: f32,
+) -> Option<(Vec2, Option<Vec2>)> {
+    let is_new_path = path.is_added();
+    if path.path.is_empty() {
+        commands.entity(entity).remove::<Path>().remove::<Target>();
+        return None;
+    }
+
+    // Skip the waypoints that are reached, or the corner when the waypoint after it is visible
+    while path.path.len() > 1 {
+        let distance = path.path[0].distance(position);
+        let cut_corner = distance < settings.corner_look_ahead
+            && temp.map_or(false, |temp| {
+                temp.is_segment_in_mesh(position, path.path[1])
+            });
+        if distance < settings.waypoint_radius || cut_corner {
+            path.path.remove(0);
+        } else {
+            break;
+        }
+    }
+
+    let distance = path.path[0].distance(position);
+    let arrived = path.path.len() == 1 && distance < navigator.radius.max(ARRIVED_DISTANCE);
+    if arrived
+        || is_stalled(
+            navigator,
+            path.path.len(),
+            distance,
+            is_new_path,
+            settings,
+            delta,
+        )
+    {
+        commands.entity(entity).remove::<Path>().remove::<Target>();
+        return None;
+    }
+
+    // Seek or arrive
+    let desired = desired_velocity(navigator, position, &path);
+    let path_end = (path.path.len() == 1).then(|| path.path[0]);
+    Some((limit_velocity(navigator, desired, delta), path_end))
+}
+
+/// Like [`steer`] for an agent following a flow field. Returns `None` and removes the
+/// [`FlowTarget`] when the agent has arrived, or replaces it with a [`Target`] when the field
+/// can't bring the agent to its target.
+#[allow(clippy::too_many_arguments)]
+fn steer_flow(
+    commands: &mut Commands,
+    entity: Entity,
+    position: Vec2,
+    flow_target: &FlowTarget,
+    navigator: &mut Navigator,
+    temp: Option<&TempNavmesh>,
+    field: Option<&FlowField>,
+    settings: &NavigationSettings,
+    delta: f32,
+) -> Option<(Vec2, Option<Vec2>)> {
+    // The field is built before the agents move, it's only missing for a frame when the
+    // navmesh changes
+    let (temp, field) = temp.zip(field)?;
+    if field.target.distance(position) < settings.flow_arrival_radius {
+        commands.entity(entity).remove::<FlowTarget>();
+        return None;
+    }
+
+    let polygon = temp.polygon_at(position).or_else(|| {
+        temp.nearest_point_where(position, settings.snap_radius, |_| true)
+            .map(|(polygon, _)| polygon)
+    });
+    let direction =
+        polygon.and_then(|polygon| field.direction(polygon, position, navigator.radius));
+    let (polygon, direction) = match polygon.zip(direction) {
+        Some(found) => found,
+        None => {
+            // Let the path search deal with it
+            commands
+                .entity(entity)
+                .remove::<FlowTarget>()
+                .insert(Target {
+                    target: flow_target.target,
+                    priority: flow_target.priority,
+                });
+            return None;
+        }
+    };
+
+    let distance = field.target.distance(position);
+    if is_stalled(navigator, polygon, distance, false, settings, delta) {
+        commands.entity(entity).remove::<FlowTarget>();
+        return None;
+    }
+
+    let (desired, path_end) = if field.is_target_polygon(polygon) {
+        let speed =
+            navigator.speed * (distance / navigator.arrival_radius.max(f32::EPSILON)).min(1.0);
+        (direction * speed, Some(field.target))
+    } else {
+        (direction * navigator.speed, None)
+    };
+    Some((limit_velocity(navigator, desired, delta), path_end))
+}
+
+/// Counts the time the agent spends without getting closer to its goal, `stage` being the
+/// waypoint or polygon it's measured from. Returns `true` when the agent should give up.
+fn is_stalled(
+    navigator: &mut Navigator,
+    stage: usize,
+    distance: f32,
+    restart: bool,
+    settings: &NavigationSettings,
+    delta: f32,
+) -> bool {
+    let (previous_stage, closest) = navigator.progress;
+    if restart || stage != previous_stage || distance < closest - PROGRESS_DISTANCE {
+        navigator.progress = (stage, distance);
+        navigator.stalled = 0.0;
+        return false;
+    }
+    navigator.stalled += delta;
+    if navigator.stalled < settings.stall_timeout {
+        return false;
+    }
+    navigator.progress = (0, f32::INFINITY);
+    navigator.stalled = 0.0;
+    true
+}
+
+/// Moves the velocity of the agent toward `desired`, limited by its acceleration and turn rate.
+fn limit_velocity(navigator: &Navigator, desired: Vec2, delta: f32) -> Vec2 {
+    // Slow down for sharp turns, so the agent doesn't orbit a waypoint it can't turn to
+    let desired = if navigator.velocity.length_squared() > f32::EPSILON
+        && desired.length_squared() > f32::EPSILON
+    {
+        let alignment = navigator.velocity.normalize().dot(desired.normalize());
+        desired * ((1.0 + alignment) / 2.0).max(0.1)
+    } else {
+        desired
+    };
+    let desired = limit_turn(navigator.velocity, desired, navigator.max_turn_rate * delta);
+    let change =
+        (desired - navigator.velocity).clamp_length_max(navigator.max_acceleration * delta);
+    (navigator.velocity + change).clamp_length_max(navigator.speed)
+}
@@ -1,6 +1,5 @@
 use crate::actions::Actions;
 // use crate::loading::TextureAssets;
-use crate::many::MyNavPlugin;
 // use crate::map::TempNavmesh;
 use crate::GameState;
 use bevy::prelude::*;
@@ -15,11 +14,10 @@ pub struct Player;
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app.add_system_set(SystemSet::on_enter(GameState::Playing).with_system(spawn_player))
-            .add_system_set(SystemSet::on_update(GameState::Playing).with_system(move_player))
-            // .add_system_set(
-            //     SystemSet::on_update(GameState::Playing).with_system(check_mouse_navmesh),
-            // )
-            .add_plugin(MyNavPlugin);
+            .add_system_set(SystemSet::on_update(GameState::Playing).with_system(move_player));
+        // .add_system_set(
+        //     SystemSet::on_update(GameState::Playing).with_system(check_mouse_navmesh),
+        // )
     }
 }
 
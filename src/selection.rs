@@ -0,0 +1,195 @@
+//! RTS-style selection of navigators: click or drag a box with the left mouse button, hold shift
+//! to add to the selection. Ctrl and a number key saves the selection as a control group, the
+//! number key alone selects it again.
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_prototype_debug_lines::DebugLines;
+
+use crate::{
+    actions::cursor_world_position, many::PathColor, map::MapEditor, navigation::Navigator,
+    GameState,
+};
+
+/// Below this distance between the press and the release, in world units, the left button
+/// clicks instead of dragging a box
+const CLICK_DISTANCE: f32 = 4.0;
+/// How far from a navigator a click selects it
+const CLICK_RADIUS: f32 = 8.0;
+const CONTROL_GROUP_KEYS: [KeyCode; 10] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::Key0,
+];
+
+pub struct SelectionPlugin;
+
+impl Plugin for SelectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ControlGroups>()
+            .init_resource::<SelectionDrag>()
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(select_navigators)
+                    .with_system(select_control_group)
+                    .with_system(highlight_selection),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(clear_selection));
+    }
+}
+
+/// A navigator receiving the orders of the player
+#[derive(Component)]
+pub struct Selected;
+
+/// Selections saved on the number keys
+#[derive(Default)]
+pub struct ControlGroups(pub HashMap<KeyCode, Vec<Entity>>);
+
+/// Where the left button was pressed, while dragging a selection box
+#[derive(Default)]
+struct SelectionDrag(Option<Vec2>);
+
+fn shift_pressed(keyboard_input: &Input<KeyCode>) -> bool {
+    keyboard_input.any_pressed([KeyCode::LShift, KeyCode::RShift])
+}
+
+/// Selects `entities`, in addition to the current selection if `add` is true
+fn set_selection(
+    commands: &mut Commands,
+    selected: &Query<Entity, With<Selected>>,
+    entities: impl IntoIterator<Item = Entity>,
+    add: bool,
+) {
+    if !add {
+        for entity in selected.iter() {
+            commands.entity(entity).remove::<Selected>();
+        }
+    }
+    for entity in entities {
+        commands.entity(entity).insert(Selected);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn select_navigators(
+    mut commands: Commands,
+    buttons: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    windows: Res<Windows>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    editor: Res<MapEditor>,
+    mut drag: ResMut<SelectionDrag>,
+    mut lines: ResMut<DebugLines>,
+    navigators: Query<(Entity, &Transform), With<Navigator>>,
+    selected: Query<Entity, With<Selected>>,
+) {
+    // The left button paints tiles in the editor
+    if editor.enabled {
+        drag.0 = None;
+        return;
+    }
+    let window = windows.get_primary().unwrap();
+    let (camera, camera_transform) = q_camera.single();
+    let cursor = match cursor_world_position(window, camera, camera_transform) {
+        Some(cursor) => cursor,
+        None => return,
+    };
+
+    if buttons.just_pressed(MouseButton::Left) {
+        drag.0 = Some(cursor);
+    }
+    let start = match drag.0 {
+        Some(start) => start,
+        None => return,
+    };
+    let (min, max) = (start.min(cursor), start.max(cursor));
+
+    if !buttons.just_released(MouseButton::Left) {
+        let corners = [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)];
+        for i in 0..corners.len() {
+            lines.line_colored(
+                corners[i].extend(100.0),
+                corners[(i + 1) % corners.len()].extend(100.0),
+                0.0,
+                Color::WHITE,
+            );
+        }
+        return;
+    }
+    drag.0 = None;
+
+    let add = shift_pressed(&keyboard_input);
+    if start.distance(cursor) < CLICK_DISTANCE {
+        let clicked = navigators
+            .iter()
+            .map(|(entity, transform)| (entity, transform.translation.truncate().distance(cursor)))
+            .filter(|(_, distance)| *distance <= CLICK_RADIUS)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(entity, _)| entity);
+        set_selection(&mut commands, &selected, clicked, add);
+    } else {
+        let in_box = navigators.iter().filter_map(|(entity, transform)| {
+            let position = transform.translation.truncate();
+            (position.cmpge(min).all() && position.cmple(max).all()).then(|| entity)
+        });
+        set_selection(&mut commands, &selected, in_box, add);
+    }
+}
+
+fn select_control_group(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut groups: ResMut<ControlGroups>,
+    navigators: Query<(), With<Navigator>>,
+    selected: Query<Entity, With<Selected>>,
+) {
+    let control = keyboard_input.any_pressed([KeyCode::LControl, KeyCode::RControl]);
+    for key in CONTROL_GROUP_KEYS {
+        if !keyboard_input.just_pressed(key) {
+            continue;
+        }
+        if control {
+            groups.0.insert(key, selected.iter().collect());
+        } else if let Some(group) = groups.0.get_mut(&key) {
+            // Forget the navigators despawned since the group was saved
+            group.retain(|entity| navigators.get(*entity).is_ok());
+            set_selection(
+                &mut commands,
+                &selected,
+                group.iter().copied(),
+                shift_pressed(&keyboard_input),
+            );
+        }
+    }
+}
+
+/// Selected navigators are drawn in white
+fn highlight_selection(
+    added: Query<Entity, Added<Selected>>,
+    removed: RemovedComponents<Selected>,
+    mut sprites: Query<(&mut Sprite, &PathColor, Option<&Selected>)>,
+) {
+    for entity in added.iter() {
+        if let Ok((mut sprite, _, _)) = sprites.get_mut(entity) {
+            sprite.color = Color::WHITE;
+        }
+    }
+    for entity in removed.iter() {
+        // Selected again in the same frame
+        if let Ok((mut sprite, color, None)) = sprites.get_mut(entity) {
+            sprite.color = color.0;
+        }
+    }
+}
+
+fn clear_selection(mut groups: ResMut<ControlGroups>, mut drag: ResMut<SelectionDrag>) {
+    groups.0.clear();
+    drag.0 = None;
+}